@@ -1,20 +1,33 @@
 use std::fs;
 
 use utils::{
-    midi_mixer::{self, MidiMixer},
+    backend, learn,
+    midi_mixer::{MidiMixer, MixerEvent},
     profile::{Profile, ProfileConfig},
 };
 
 mod utils;
 
+const CONFIG_PATH: &str = "config.toml";
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let toml = fs::read_to_string("config.toml")?;
+    let toml = fs::read_to_string(CONFIG_PATH)?;
     let config: ProfileConfig = toml::from_str(&toml)?;
     let profile = Profile::new(&config)?;
 
-    let mut midi_mixer = MidiMixer::new(Profile::new(&config)?).unwrap();
-
-    loop {
-        midi_mixer.update()?;
+    if std::env::args().any(|arg| arg == "--learn") {
+        return learn::run(&profile, &toml, CONFIG_PATH);
     }
+
+    let backend = backend::connect(&profile)?;
+    let midi_mixer = MidiMixer::new(profile, backend)?;
+
+    let sender = midi_mixer.event_sender();
+    ctrlc::set_handler(move || {
+        let _ = sender.send(MixerEvent::Shutdown);
+    })?;
+
+    midi_mixer.run()?;
+
+    Ok(())
 }
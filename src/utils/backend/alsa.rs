@@ -0,0 +1,281 @@
+use std::{collections::HashMap, thread};
+
+use alsa::{
+    card,
+    mixer::{Selem, SelemChannelId, SelemId},
+    poll, Mixer,
+};
+
+use super::{AudioBackend, BackendError, StreamInfo, StreamKind};
+
+// Mapping targets look like alsa:hw:0/Master
+const TARGET_PREFIX: &str = "alsa:";
+
+impl From<alsa::Error> for BackendError {
+    fn from(error: alsa::Error) -> Self {
+        BackendError::Operation(error.to_string())
+    }
+}
+
+// Name of a simple element as it is written in the mapping
+fn target_name(card: &str, element: &str, index: u32) -> String {
+    match index {
+        0 => format!("{}{}/{}", TARGET_PREFIX, card, element),
+        _ => format!("{}{}/{},{}", TARGET_PREFIX, card, element, index),
+    }
+}
+
+fn to_percentage(value: i64, (min, max): (i64, i64)) -> f64 {
+    if max <= min {
+        return 0.0;
+    }
+    (value - min) as f64 / (max - min) as f64
+}
+
+fn from_percentage(volume: f64, (min, max): (i64, i64)) -> i64 {
+    min + (volume.clamp(0.0, 1.0) * (max - min) as f64).round() as i64
+}
+
+fn card_names() -> Result<Vec<String>, BackendError> {
+    card::Iter::new()
+        .map(|card| Ok(format!("hw:{}", card?.get_index())))
+        .collect()
+}
+
+// Simple mixer element of a card, playback controls win over capture ones
+struct Element {
+    card: String,
+    name: String,
+    index: u32,
+    // Elements like "Capture" only have capture controls
+    capture: bool,
+}
+
+pub struct Alsa {
+    mixers: HashMap<String, Mixer>,
+    elements: HashMap<u32, Element>,
+}
+
+impl Alsa {
+    pub fn new() -> Result<Alsa, BackendError> {
+        let mixers = card_names()?
+            .into_iter()
+            .map(|card| {
+                let mixer = Mixer::new(&card, false)
+                    .map_err(|e| BackendError::Connection(format!("{}: {}", card, e)))?;
+                Ok((card, mixer))
+            })
+            .collect::<Result<HashMap<String, Mixer>, BackendError>>()?;
+
+        Ok(Alsa {
+            mixers,
+            elements: HashMap::new(),
+        })
+    }
+
+    // Enumerates every element with a volume again, indices stay the same as long as
+    // the cards do
+    fn list(&mut self, capture: bool) -> Result<Vec<StreamInfo>, BackendError> {
+        let mut cards: Vec<&String> = self.mixers.keys().collect();
+        cards.sort();
+
+        let mut elements = HashMap::new();
+        for card in cards {
+            let mixer = &self.mixers[card];
+            mixer.handle_events()?;
+            for selem in mixer.iter().filter_map(Selem::new) {
+                if !selem.has_volume() {
+                    continue;
+                }
+                let id = selem.get_id();
+                let element = Element {
+                    card: card.clone(),
+                    name: id.get_name()?.to_string(),
+                    index: id.get_index(),
+                    capture: !selem.has_playback_volume(),
+                };
+                elements.insert(elements.len() as u32, element);
+            }
+        }
+        self.elements = elements;
+
+        let mut streams: Vec<StreamInfo> = self
+            .elements
+            .iter()
+            .filter(|(_, element)| element.capture == capture)
+            .map(|(&index, element)| StreamInfo {
+                index,
+                name: target_name(&element.card, &element.name, element.index),
+            })
+            .collect();
+        streams.sort_by_key(|stream| stream.index);
+        Ok(streams)
+    }
+
+    // Runs the element through the current state of its card
+    fn with_selem<T>(
+        &mut self,
+        index: u32,
+        f: impl FnOnce(&Selem, bool) -> Result<T, BackendError>,
+    ) -> Result<T, BackendError> {
+        let element = self
+            .elements
+            .get(&index)
+            .ok_or_else(|| BackendError::Operation(format!("No ALSA element {}", index)))?;
+        let mixer = self
+            .mixers
+            .get(&element.card)
+            .ok_or_else(|| BackendError::Operation(format!("No ALSA card {}", element.card)))?;
+
+        // Pulls in changes made by other programs since the last call
+        mixer.handle_events()?;
+        let selem = mixer
+            .find_selem(&SelemId::new(&element.name, element.index))
+            .ok_or_else(|| {
+                BackendError::Operation(format!(
+                    "ALSA element {} is gone",
+                    target_name(&element.card, &element.name, element.index)
+                ))
+            })?;
+        f(&selem, element.capture)
+    }
+}
+
+impl AudioBackend for Alsa {
+    // Streams of applications only exist with a sound server
+    fn list_applications(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(Vec::new())
+    }
+
+    // Simple elements with a playback volume
+    fn list_devices(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        self.list(false)
+    }
+
+    // Simple elements that only have a capture volume, like "Capture"
+    fn list_sources(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        self.list(true)
+    }
+
+    fn list_recording_streams(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(Vec::new())
+    }
+
+    fn list_gain_stages(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(Vec::new())
+    }
+
+    fn get_volume(&mut self, _kind: StreamKind, index: u32) -> Result<f64, BackendError> {
+        self.with_selem(index, |selem, capture| {
+            let channel = SelemChannelId::mono();
+            Ok(match capture {
+                true => to_percentage(
+                    selem.get_capture_volume(channel)?,
+                    selem.get_capture_volume_range(),
+                ),
+                false => to_percentage(
+                    selem.get_playback_volume(channel)?,
+                    selem.get_playback_volume_range(),
+                ),
+            })
+        })
+    }
+
+    fn set_volume(
+        &mut self,
+        _kind: StreamKind,
+        index: u32,
+        volume: f64,
+    ) -> Result<(), BackendError> {
+        self.with_selem(index, |selem, capture| {
+            match capture {
+                true => selem.set_capture_volume_all(from_percentage(
+                    volume,
+                    selem.get_capture_volume_range(),
+                ))?,
+                false => selem.set_playback_volume_all(from_percentage(
+                    volume,
+                    selem.get_playback_volume_range(),
+                ))?,
+            }
+            Ok(())
+        })
+    }
+
+    // Elements without a switch can't be muted and always report unmuted
+    fn is_muted(&mut self, _kind: StreamKind, index: u32) -> Result<bool, BackendError> {
+        self.with_selem(index, |selem, capture| {
+            let channel = SelemChannelId::mono();
+            Ok(match capture {
+                true if selem.has_capture_switch() => selem.get_capture_switch(channel)? == 0,
+                false if selem.has_playback_switch() => selem.get_playback_switch(channel)? == 0,
+                _ => false,
+            })
+        })
+    }
+
+    // Like is_muted, elements without a switch are left alone
+    fn set_mute(&mut self, _kind: StreamKind, index: u32, mute: bool) -> Result<(), BackendError> {
+        // The switch is on while the element is audible
+        let switch = if mute { 0 } else { 1 };
+        self.with_selem(index, |selem, capture| {
+            match capture {
+                true if selem.has_capture_switch() => selem.set_capture_switch_all(switch)?,
+                false if selem.has_playback_switch() => selem.set_playback_switch_all(switch)?,
+                _ => eprintln!(
+                    "ALSA element {} has no mute switch, ignoring mute",
+                    selem.get_id().get_name()?
+                ),
+            }
+            Ok(())
+        })
+    }
+
+    fn subscribe(&mut self, callback: Box<dyn Fn() + Send>) -> Result<(), BackendError> {
+        // Mixers of their own, events are only delivered to the mixer that polls
+        let mixers = card_names()?
+            .iter()
+            .map(|card| Mixer::new(card, false))
+            .collect::<Result<Vec<Mixer>, alsa::Error>>()?;
+
+        thread::spawn(move || loop {
+            let descriptors: Vec<&dyn poll::Descriptors> = mixers
+                .iter()
+                .map(|mixer| mixer as &dyn poll::Descriptors)
+                .collect();
+            if let Err(e) = poll::poll_all(&descriptors, -1) {
+                eprintln!("Stopped watching ALSA for changes: {}", e);
+                return;
+            }
+            for mixer in &mixers {
+                let _ = mixer.handle_events();
+            }
+            callback();
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_names_match_the_mapping() {
+        assert_eq!(target_name("hw:0", "Master", 0), "alsa:hw:0/Master");
+        assert_eq!(target_name("hw:1", "Capture", 1), "alsa:hw:1/Capture,1");
+    }
+
+    #[test]
+    fn volume_range_maps_to_percentage() {
+        assert_eq!(to_percentage(0, (0, 87)), 0.0);
+        assert_eq!(to_percentage(87, (0, 87)), 1.0);
+        assert_eq!(to_percentage(-10, (-20, 0)), 0.5);
+        assert_eq!(to_percentage(5, (5, 5)), 0.0);
+
+        assert_eq!(from_percentage(0.5, (0, 87)), 44);
+        assert_eq!(from_percentage(1.5, (0, 87)), 87);
+        assert_eq!(from_percentage(0.5, (-20, 0)), -10);
+    }
+}
@@ -0,0 +1,229 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+
+use jack::{
+    AsyncClient, AudioIn, AudioOut, Client, ClientOptions, Control, Port, ProcessHandler,
+    ProcessScope,
+};
+
+use super::{AudioBackend, BackendError, StreamInfo, StreamKind};
+
+const CLIENT_NAME: &str = "MidiMixer";
+// Every gain stage is a stereo pair of inputs and outputs
+const CHANNELS: usize = 2;
+
+impl From<jack::Error> for BackendError {
+    fn from(error: jack::Error) -> Self {
+        BackendError::Operation(error.to_string())
+    }
+}
+
+// Faders feel like the ones of a sound server when the gain is the cube of the position
+fn to_gain(volume: f64) -> f32 {
+    volume.max(0.0).powi(3) as f32
+}
+
+// Ramps from the gain of the last buffer to the new one so a jumping fader doesn't click
+fn apply_gain(input: &[f32], output: &mut [f32], from: f32, to: f32) {
+    let frames = output.len() as f32;
+    for (n, (out, sample)) in output.iter_mut().zip(input).enumerate() {
+        let gain = from + (to - from) * (n + 1) as f32 / frames;
+        *out = sample * gain;
+    }
+}
+
+// Set from the mixer thread, read by the process callback
+struct Stage {
+    name: String,
+    // Bits of the f64 volume, 0.0 to 1.0
+    volume: AtomicU64,
+    muted: AtomicBool,
+}
+
+impl Stage {
+    fn volume(&self) -> f64 {
+        f64::from_bits(self.volume.load(Ordering::Relaxed))
+    }
+
+    fn gain(&self) -> f32 {
+        match self.muted.load(Ordering::Relaxed) {
+            true => 0.0,
+            false => to_gain(self.volume()),
+        }
+    }
+}
+
+// Inputs + outputs of a stage, one port per channel
+type StagePorts = (Vec<Port<AudioIn>>, Vec<Port<AudioOut>>);
+
+struct GainStages {
+    stages: Arc<Vec<Stage>>,
+    ports: Vec<StagePorts>,
+    // Gain each stage ended the last buffer with
+    gains: Vec<f32>,
+}
+
+impl ProcessHandler for GainStages {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> Control {
+        for (index, (inputs, outputs)) in self.ports.iter_mut().enumerate() {
+            let gain = self.stages[index].gain();
+            for (input, output) in inputs.iter().zip(outputs.iter_mut()) {
+                apply_gain(
+                    input.as_slice(ps),
+                    output.as_mut_slice(ps),
+                    self.gains[index],
+                    gain,
+                );
+            }
+            self.gains[index] = gain;
+        }
+        Control::Continue
+    }
+}
+
+// A JACK client with an input and output pair per mapped group, audio routed through
+// it is scaled by the group's fader
+pub struct Jack {
+    stages: Arc<Vec<Stage>>,
+    _client: AsyncClient<(), GainStages>,
+}
+
+impl Jack {
+    pub fn new(groups: &[String]) -> Result<Jack, BackendError> {
+        let (client, _) = Client::new(CLIENT_NAME, ClientOptions::NO_START_SERVER)
+            .map_err(|e| BackendError::Connection(e.to_string()))?;
+
+        let mut ports = Vec::new();
+        for group in groups {
+            let inputs = (1..=CHANNELS)
+                .map(|channel| client.register_port(&format!("{}_in_{}", group, channel), AudioIn))
+                .collect::<Result<Vec<Port<AudioIn>>, jack::Error>>()?;
+            let outputs = (1..=CHANNELS)
+                .map(|channel| {
+                    client.register_port(&format!("{}_out_{}", group, channel), AudioOut)
+                })
+                .collect::<Result<Vec<Port<AudioOut>>, jack::Error>>()?;
+            ports.push((inputs, outputs));
+        }
+
+        // Unity gain until a fader moves
+        let stages = Arc::new(
+            groups
+                .iter()
+                .map(|group| Stage {
+                    name: group.clone(),
+                    volume: AtomicU64::new(1.0f64.to_bits()),
+                    muted: AtomicBool::new(false),
+                })
+                .collect::<Vec<Stage>>(),
+        );
+        let process = GainStages {
+            stages: stages.clone(),
+            ports,
+            gains: vec![1.0; groups.len()],
+        };
+
+        Ok(Jack {
+            stages,
+            _client: client
+                .activate_async((), process)
+                .map_err(|e| BackendError::Connection(e.to_string()))?,
+        })
+    }
+
+    fn stage(&self, kind: StreamKind, index: u32) -> Result<&Stage, BackendError> {
+        self.stages
+            .get(index as usize)
+            .filter(|_| kind == StreamKind::GainStage)
+            .ok_or_else(|| BackendError::Operation(format!("No JACK gain stage {}", index)))
+    }
+}
+
+impl AudioBackend for Jack {
+    fn list_applications(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(Vec::new())
+    }
+
+    fn list_devices(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(Vec::new())
+    }
+
+    fn list_sources(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(Vec::new())
+    }
+
+    fn list_recording_streams(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(Vec::new())
+    }
+
+    // Named after the groups they were created for
+    fn list_gain_stages(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(self
+            .stages
+            .iter()
+            .enumerate()
+            .map(|(index, stage)| StreamInfo {
+                index: index as u32,
+                name: stage.name.clone(),
+            })
+            .collect())
+    }
+
+    fn get_volume(&mut self, kind: StreamKind, index: u32) -> Result<f64, BackendError> {
+        Ok(self.stage(kind, index)?.volume())
+    }
+
+    fn set_volume(
+        &mut self,
+        kind: StreamKind,
+        index: u32,
+        volume: f64,
+    ) -> Result<(), BackendError> {
+        self.stage(kind, index)?
+            .volume
+            .store(volume.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn is_muted(&mut self, kind: StreamKind, index: u32) -> Result<bool, BackendError> {
+        Ok(self.stage(kind, index)?.muted.load(Ordering::Relaxed))
+    }
+
+    fn set_mute(&mut self, kind: StreamKind, index: u32, mute: bool) -> Result<(), BackendError> {
+        self.stage(kind, index)?
+            .muted
+            .store(mute, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Only the mixer itself changes the gain stages, there is nothing to watch
+    fn subscribe(&mut self, _callback: Box<dyn Fn() + Send>) -> Result<(), BackendError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gain_follows_fader_curve() {
+        assert_eq!(to_gain(0.0), 0.0);
+        assert_eq!(to_gain(0.5), 0.125);
+        assert_eq!(to_gain(1.0), 1.0);
+    }
+
+    #[test]
+    fn gain_ramps_over_the_buffer() {
+        let input = [1.0; 4];
+        let mut output = [0.0; 4];
+
+        apply_gain(&input, &mut output, 1.0, 0.0);
+        assert_eq!(output, [0.75, 0.5, 0.25, 0.0]);
+
+        apply_gain(&input, &mut output, 0.5, 0.5);
+        assert_eq!(output, [0.5; 4]);
+    }
+}
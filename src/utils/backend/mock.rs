@@ -0,0 +1,159 @@
+use super::{AudioBackend, BackendError, StreamInfo, StreamKind};
+
+// Every write the mixer made, in order
+#[derive(Debug, Clone, PartialEq)]
+pub enum Call {
+    SetVolume(StreamKind, u32, f64),
+    SetMute(StreamKind, u32, bool),
+}
+
+struct MockStream {
+    kind: StreamKind,
+    info: StreamInfo,
+    volume: f64,
+    muted: bool,
+}
+
+// In-memory sound server for tests, streams are added and changed by the test itself
+#[derive(Default)]
+pub struct MockBackend {
+    streams: Vec<MockStream>,
+    next_index: u32,
+    calls: Vec<Call>,
+    callback: Option<Box<dyn Fn() + Send>>,
+    // Returned by the next write instead of changing anything
+    failure: Option<BackendError>,
+}
+
+impl MockBackend {
+    // Returns the index of the new stream
+    pub fn add(&mut self, kind: StreamKind, name: &str, volume: f64) -> u32 {
+        let index = self.next_index;
+        self.next_index += 1;
+        self.streams.push(MockStream {
+            kind,
+            info: StreamInfo {
+                index,
+                name: name.to_string(),
+            },
+            volume,
+            muted: false,
+        });
+        index
+    }
+
+    pub fn remove(&mut self, kind: StreamKind, index: u32) {
+        self.streams
+            .retain(|stream| stream.kind != kind || stream.info.index != index);
+    }
+
+    pub fn volume(&self, kind: StreamKind, index: u32) -> f64 {
+        self.find(kind, index).unwrap().volume
+    }
+
+    pub fn muted(&self, kind: StreamKind, index: u32) -> bool {
+        self.find(kind, index).unwrap().muted
+    }
+
+    // Changes a stream like another application would, without recording a call
+    pub fn change(&mut self, kind: StreamKind, index: u32, volume: f64, muted: bool) {
+        let stream = self.find_mut(kind, index).unwrap();
+        stream.volume = volume;
+        stream.muted = muted;
+
+        if let Some(callback) = &self.callback {
+            callback();
+        }
+    }
+
+    pub fn fail_next_write(&mut self, error: BackendError) {
+        self.failure = Some(error);
+    }
+
+    pub fn calls(&self) -> &[Call] {
+        &self.calls
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.callback.is_some()
+    }
+
+    fn find(&self, kind: StreamKind, index: u32) -> Option<&MockStream> {
+        self.streams
+            .iter()
+            .find(|stream| stream.kind == kind && stream.info.index == index)
+    }
+
+    fn find_mut(&mut self, kind: StreamKind, index: u32) -> Result<&mut MockStream, BackendError> {
+        self.streams
+            .iter_mut()
+            .find(|stream| stream.kind == kind && stream.info.index == index)
+            .ok_or_else(|| BackendError::Operation(format!("No {:?} {}", kind, index)))
+    }
+
+    fn list(&self, kind: StreamKind) -> Vec<StreamInfo> {
+        self.streams
+            .iter()
+            .filter(|stream| stream.kind == kind)
+            .map(|stream| stream.info.clone())
+            .collect()
+    }
+}
+
+impl AudioBackend for MockBackend {
+    fn list_applications(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(self.list(StreamKind::Application))
+    }
+
+    fn list_devices(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(self.list(StreamKind::OutputDevice))
+    }
+
+    fn list_sources(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(self.list(StreamKind::InputDevice))
+    }
+
+    fn list_recording_streams(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(self.list(StreamKind::RecordingStream))
+    }
+
+    fn list_gain_stages(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(self.list(StreamKind::GainStage))
+    }
+
+    fn get_volume(&mut self, kind: StreamKind, index: u32) -> Result<f64, BackendError> {
+        Ok(self.find_mut(kind, index)?.volume)
+    }
+
+    fn set_volume(
+        &mut self,
+        kind: StreamKind,
+        index: u32,
+        volume: f64,
+    ) -> Result<(), BackendError> {
+        if let Some(error) = self.failure.take() {
+            return Err(error);
+        }
+        self.find_mut(kind, index)?.volume = volume;
+        self.calls.push(Call::SetVolume(kind, index, volume));
+        Ok(())
+    }
+
+    fn is_muted(&mut self, kind: StreamKind, index: u32) -> Result<bool, BackendError> {
+        Ok(self.find_mut(kind, index)?.muted)
+    }
+
+    fn set_mute(&mut self, kind: StreamKind, index: u32, mute: bool) -> Result<(), BackendError> {
+        if let Some(error) = self.failure.take() {
+            return Err(error);
+        }
+        self.find_mut(kind, index)?.muted = mute;
+        self.calls.push(Call::SetMute(kind, index, mute));
+        Ok(())
+    }
+
+    fn subscribe(&mut self, callback: Box<dyn Fn() + Send>) -> Result<(), BackendError> {
+        self.callback = Some(callback);
+        Ok(())
+    }
+}
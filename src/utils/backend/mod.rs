@@ -0,0 +1,145 @@
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use serde::{Deserialize, Serialize};
+
+use super::profile::Profile;
+
+#[cfg(feature = "alsa")]
+pub mod alsa;
+#[cfg(feature = "jack")]
+pub mod jack;
+#[cfg(test)]
+pub mod mock;
+#[cfg(feature = "pipewire")]
+pub mod pipewire;
+#[cfg(feature = "pulseaudio")]
+pub mod pulse;
+
+// Sound server the profile talks to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    #[default]
+    Pulseaudio,
+    Pipewire,
+    // Simple mixer elements of every card, for systems without a sound server
+    Alsa,
+    // Gain stages of a JACK client of our own, one per mapped group
+    Jack,
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendKind::Pulseaudio => write!(f, "pulseaudio"),
+            BackendKind::Pipewire => write!(f, "pipewire"),
+            BackendKind::Alsa => write!(f, "alsa"),
+            BackendKind::Jack => write!(f, "jack"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum BackendError {
+    // The sound server can't be reached
+    Connection(String),
+    Operation(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendError::Connection(e) => write!(f, "Audio backend connection failed: {}", e),
+            BackendError::Operation(e) => write!(f, "Audio backend operation failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StreamKind {
+    // Playback stream of an application
+    Application,
+    OutputDevice,
+    // Microphone or other capture device
+    InputDevice,
+    // Capture stream of an application
+    RecordingStream,
+    // Ports of the mixer itself that audio is routed through, one per group on JACK
+    GainStage,
+}
+
+pub const GAIN_STAGE_PREFIX: &str = "stage:";
+
+impl StreamKind {
+    // Mapping targets may start with app:, device:, source: or record: to only match
+    // streams of that kind, like record:obs next to an OBS playback stream. Groups on
+    // JACK target their own gain stage as stage:<group>.
+    pub fn split_target(target: &str) -> (Option<StreamKind>, &str) {
+        let kinds = [
+            ("app:", StreamKind::Application),
+            ("device:", StreamKind::OutputDevice),
+            ("source:", StreamKind::InputDevice),
+            ("record:", StreamKind::RecordingStream),
+            (GAIN_STAGE_PREFIX, StreamKind::GainStage),
+        ];
+        kinds
+            .iter()
+            .find_map(|&(prefix, kind)| Some((Some(kind), target.strip_prefix(prefix)?)))
+            .unwrap_or((None, target))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    pub index: u32,
+    // Binary name for applications and recording streams, description for devices
+    pub name: String,
+}
+
+// Everything the mixer needs from a sound server, volumes are 0.0 to 1.0 at 100%
+pub trait AudioBackend {
+    fn list_applications(&mut self) -> Result<Vec<StreamInfo>, BackendError>;
+    fn list_devices(&mut self) -> Result<Vec<StreamInfo>, BackendError>;
+    fn list_sources(&mut self) -> Result<Vec<StreamInfo>, BackendError>;
+    fn list_recording_streams(&mut self) -> Result<Vec<StreamInfo>, BackendError>;
+    fn list_gain_stages(&mut self) -> Result<Vec<StreamInfo>, BackendError>;
+
+    fn get_volume(&mut self, kind: StreamKind, index: u32) -> Result<f64, BackendError>;
+    fn set_volume(&mut self, kind: StreamKind, index: u32, volume: f64)
+        -> Result<(), BackendError>;
+    fn is_muted(&mut self, kind: StreamKind, index: u32) -> Result<bool, BackendError>;
+    fn set_mute(&mut self, kind: StreamKind, index: u32, mute: bool) -> Result<(), BackendError>;
+
+    // Calls back from a thread of the backend whenever a stream or device changes
+    fn subscribe(&mut self, callback: Box<dyn Fn() + Send>) -> Result<(), BackendError>;
+}
+
+// Connects to the sound server selected by the profile, if the binary was built with it
+pub fn connect(profile: &Profile) -> Result<Rc<RefCell<dyn AudioBackend>>, BackendError> {
+    match profile.get_backend() {
+        #[cfg(feature = "pulseaudio")]
+        BackendKind::Pulseaudio => Ok(Rc::new(RefCell::new(pulse::PulseAudio::new()?))),
+        #[cfg(feature = "pipewire")]
+        BackendKind::Pipewire => Ok(Rc::new(RefCell::new(pipewire::PipeWire::new()?))),
+        #[cfg(feature = "alsa")]
+        BackendKind::Alsa => Ok(Rc::new(RefCell::new(alsa::Alsa::new()?))),
+        #[cfg(feature = "jack")]
+        BackendKind::Jack => {
+            // Groups without a target are switched off, they get no ports
+            let groups: Vec<String> = profile
+                .get_groups()
+                .into_iter()
+                .filter(|(_, target)| !target.is_empty())
+                .map(|(group, _)| group)
+                .collect();
+            Ok(Rc::new(RefCell::new(jack::Jack::new(&groups)?)))
+        }
+        #[allow(unreachable_patterns)]
+        kind => Err(BackendError::Connection(format!(
+            "Built without the {} backend, enable the {} feature",
+            kind, kind
+        ))),
+    }
+}
@@ -0,0 +1,533 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::Cursor,
+    rc::Rc,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use pipewire::{
+    context::Context,
+    core::PW_ID_CORE,
+    main_loop::MainLoop,
+    node::{Node, NodeListener},
+    registry::GlobalObject,
+    spa::{
+        self,
+        param::ParamType,
+        pod::{
+            deserialize::PodDeserializer, serialize::PodSerializer, Object, Pod, Property, Value,
+            ValueArray,
+        },
+        utils::{dict::DictRef, SpaTypes},
+    },
+    types::ObjectType,
+};
+
+use super::{AudioBackend, BackendError, StreamInfo, StreamKind};
+
+// How long connecting may take until the first round trip with the daemon is done
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct NodeState {
+    kind: StreamKind,
+    name: String,
+    // Linear per channel volumes as PipeWire keeps them
+    volumes: Vec<f32>,
+    muted: bool,
+}
+
+enum Command {
+    SetVolumes(u32, Vec<f32>),
+    SetMute(u32, bool),
+}
+
+type Nodes = Arc<Mutex<HashMap<u32, NodeState>>>;
+type Callback = Arc<Mutex<Option<Box<dyn Fn() + Send>>>>;
+
+fn notify(callback: &Callback) {
+    if let Some(callback) = callback.lock().unwrap().as_ref() {
+        callback();
+    }
+}
+
+fn stream_kind(media_class: &str) -> Option<StreamKind> {
+    match media_class {
+        "Stream/Output/Audio" => Some(StreamKind::Application),
+        "Stream/Input/Audio" => Some(StreamKind::RecordingStream),
+        "Audio/Sink" => Some(StreamKind::OutputDevice),
+        "Audio/Source" => Some(StreamKind::InputDevice),
+        _ => None,
+    }
+}
+
+// Binary name for applications like PulseAudio reports it, description for devices
+fn node_name(kind: StreamKind, props: &DictRef) -> Option<String> {
+    let keys: &[&str] = match kind {
+        StreamKind::Application | StreamKind::RecordingStream => {
+            &["application.process.binary", "application.name"]
+        }
+        _ => &["node.description", "node.nick", "node.name"],
+    };
+    keys.iter()
+        .find_map(|key| props.get(key))
+        .map(|name| name.to_string())
+}
+
+// PipeWire volumes are linear, the percentages everyone shows are their cube root
+fn to_percentage(volume: f32) -> f64 {
+    (volume.max(0.0) as f64).cbrt()
+}
+
+fn from_percentage(volume: f64) -> f32 {
+    volume.max(0.0).powi(3) as f32
+}
+
+fn props_pod(properties: Vec<Property>) -> Option<Vec<u8>> {
+    let object = Value::Object(Object {
+        type_: SpaTypes::ObjectParamProps.as_raw(),
+        id: ParamType::Props.as_raw(),
+        properties,
+    });
+    PodSerializer::serialize(Cursor::new(Vec::new()), &object)
+        .ok()
+        .map(|(cursor, _)| cursor.into_inner())
+}
+
+// Picks channel volumes and mute out of a Props param
+fn parse_props(pod: &Pod) -> (Option<Vec<f32>>, Option<bool>) {
+    let Ok((_, Value::Object(object))) = PodDeserializer::deserialize_any_from(pod.as_bytes())
+    else {
+        return (None, None);
+    };
+
+    let mut volumes = None;
+    let mut muted = None;
+    for property in object.properties {
+        match (property.key, property.value) {
+            (spa::sys::SPA_PROP_channelVolumes, Value::ValueArray(ValueArray::Float(values))) => {
+                volumes = Some(values)
+            }
+            (spa::sys::SPA_PROP_mute, Value::Bool(value)) => muted = Some(value),
+            _ => {}
+        }
+    }
+    (volumes, muted)
+}
+
+fn bind_node(
+    registry: &pipewire::registry::Registry,
+    global: &GlobalObject<&DictRef>,
+    nodes: &Nodes,
+    callback: &Callback,
+) -> Option<(Node, NodeListener)> {
+    if global.type_ != ObjectType::Node {
+        return None;
+    }
+    let kind = stream_kind(global.props?.get("media.class")?)?;
+    let node: Node = registry.bind(global).ok()?;
+    let id = global.id;
+
+    nodes.lock().unwrap().insert(
+        id,
+        NodeState {
+            kind,
+            name: node_name(kind, global.props?).unwrap_or_default(),
+            volumes: Vec::new(),
+            muted: false,
+        },
+    );
+
+    let info_nodes = nodes.clone();
+    let info_callback = callback.clone();
+    let param_nodes = nodes.clone();
+    let param_callback = callback.clone();
+    let listener = node
+        .add_listener_local()
+        .info(move |info| {
+            // Only the full properties carry the application binary
+            let Some(name) = info.props().and_then(|props| node_name(kind, props)) else {
+                return;
+            };
+            if let Some(state) = info_nodes.lock().unwrap().get_mut(&id) {
+                state.name = name;
+            }
+            notify(&info_callback);
+        })
+        .param(move |_, _, _, _, param| {
+            let Some((volumes, muted)) = param.map(parse_props) else {
+                return;
+            };
+            if let Some(state) = param_nodes.lock().unwrap().get_mut(&id) {
+                state.volumes = volumes.unwrap_or(state.volumes.clone());
+                state.muted = muted.unwrap_or(state.muted);
+            }
+            notify(&param_callback);
+        })
+        .register();
+    node.subscribe_params(&[ParamType::Props]);
+
+    Some((node, listener))
+}
+
+// Owns the PipeWire connection, every proxy lives on this thread
+fn run(
+    nodes: Nodes,
+    callback: Callback,
+    commands: pipewire::channel::Receiver<Command>,
+    ready: mpsc::Sender<()>,
+) -> Result<(), pipewire::Error> {
+    let main_loop = MainLoop::new(None)?;
+    let context = Context::new(&main_loop)?;
+    let core = context.connect(None)?;
+    let registry = Rc::new(core.get_registry()?);
+    let proxies: Rc<RefCell<HashMap<u32, (Node, NodeListener)>>> = Rc::default();
+
+    let command_proxies = proxies.clone();
+    let _commands = commands.attach(main_loop.loop_(), move |command| {
+        let (id, property) = match command {
+            Command::SetVolumes(id, volumes) => (
+                id,
+                Property::new(
+                    spa::sys::SPA_PROP_channelVolumes,
+                    Value::ValueArray(ValueArray::Float(volumes)),
+                ),
+            ),
+            Command::SetMute(id, mute) => (
+                id,
+                Property::new(spa::sys::SPA_PROP_mute, Value::Bool(mute)),
+            ),
+        };
+
+        let proxies = command_proxies.borrow();
+        let Some((node, _)) = proxies.get(&id) else {
+            return;
+        };
+        if let Some(pod) = props_pod(vec![property])
+            .as_deref()
+            .and_then(Pod::from_bytes)
+        {
+            node.set_param(ParamType::Props, 0, pod);
+        }
+    });
+
+    let global_registry = registry.clone();
+    let global_proxies = proxies.clone();
+    let global_nodes = nodes.clone();
+    let global_callback = callback.clone();
+    let remove_callback = callback.clone();
+    let _registry_listener = registry
+        .add_listener_local()
+        .global(move |global| {
+            if let Some(proxy) =
+                bind_node(&global_registry, global, &global_nodes, &global_callback)
+            {
+                global_proxies.borrow_mut().insert(global.id, proxy);
+            }
+        })
+        .global_remove(move |id| {
+            if proxies.borrow_mut().remove(&id).is_some() {
+                nodes.lock().unwrap().remove(&id);
+                notify(&remove_callback);
+            }
+        })
+        .register();
+
+    // Every node that exists right now has been announced once the daemon answers this
+    let pending = core.sync(0)?;
+    let _core_listener = core
+        .add_listener_local()
+        .done(move |id, seq| {
+            if id == PW_ID_CORE && seq == pending {
+                let _ = ready.send(());
+            }
+        })
+        .register();
+
+    main_loop.run();
+    Ok(())
+}
+
+pub struct PipeWire {
+    nodes: Nodes,
+    callback: Callback,
+    commands: pipewire::channel::Sender<Command>,
+}
+
+impl PipeWire {
+    pub fn new() -> Result<PipeWire, BackendError> {
+        let nodes = Nodes::default();
+        let callback = Callback::default();
+        let (commands, command_receiver) = pipewire::channel::channel();
+        let (ready, ready_receiver) = mpsc::channel();
+
+        let thread_nodes = nodes.clone();
+        let thread_callback = callback.clone();
+        thread::spawn(move || {
+            if let Err(e) = run(thread_nodes, thread_callback, command_receiver, ready) {
+                eprintln!("PipeWire connection failed: {}", e);
+            }
+        });
+
+        ready_receiver
+            .recv_timeout(CONNECT_TIMEOUT)
+            .map_err(|_| BackendError::Connection("No answer from PipeWire".to_string()))?;
+
+        Ok(PipeWire {
+            nodes,
+            callback,
+            commands,
+        })
+    }
+
+    fn list(&self, kind: StreamKind) -> Vec<StreamInfo> {
+        let mut streams: Vec<StreamInfo> = self
+            .nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, state)| state.kind == kind)
+            .map(|(&index, state)| StreamInfo {
+                index,
+                name: state.name.clone(),
+            })
+            .collect();
+        streams.sort_by_key(|stream| stream.index);
+        streams
+    }
+
+    fn with_node<T>(
+        &self,
+        kind: StreamKind,
+        index: u32,
+        f: impl FnOnce(&mut NodeState) -> T,
+    ) -> Result<T, BackendError> {
+        match self.nodes.lock().unwrap().get_mut(&index) {
+            Some(state) if state.kind == kind => Ok(f(state)),
+            _ => Err(BackendError::Operation(format!(
+                "No PipeWire node {} of kind {:?}",
+                index, kind
+            ))),
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<(), BackendError> {
+        self.commands
+            .send(command)
+            .map_err(|_| BackendError::Connection("PipeWire thread is gone".to_string()))
+    }
+}
+
+impl AudioBackend for PipeWire {
+    fn list_applications(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(self.list(StreamKind::Application))
+    }
+
+    fn list_devices(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(self.list(StreamKind::OutputDevice))
+    }
+
+    fn list_sources(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(self.list(StreamKind::InputDevice))
+    }
+
+    fn list_recording_streams(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(self.list(StreamKind::RecordingStream))
+    }
+
+    fn list_gain_stages(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(Vec::new())
+    }
+
+    fn get_volume(&mut self, kind: StreamKind, index: u32) -> Result<f64, BackendError> {
+        self.with_node(kind, index, |state| {
+            let loudest = state.volumes.iter().cloned().fold(0.0, f32::max);
+            to_percentage(loudest)
+        })
+    }
+
+    fn set_volume(
+        &mut self,
+        kind: StreamKind,
+        index: u32,
+        volume: f64,
+    ) -> Result<(), BackendError> {
+        let volumes = self.with_node(kind, index, |state| {
+            // Without its Props param there is no telling how many channels the node has
+            if state.volumes.is_empty() {
+                return Err(BackendError::Operation(format!(
+                    "PipeWire node {} has not reported its volume yet",
+                    index
+                )));
+            }
+            let loudest = state.volumes.iter().cloned().fold(0.0, f32::max);
+            // Scale every channel by the same factor to keep the balance
+            let volumes: Vec<f32> = if loudest > 0.0 {
+                state
+                    .volumes
+                    .iter()
+                    .map(|&channel| {
+                        from_percentage(to_percentage(channel) * volume / to_percentage(loudest))
+                    })
+                    .collect()
+            } else {
+                vec![from_percentage(volume); state.volumes.len()]
+            };
+            state.volumes = volumes.clone();
+            Ok(volumes)
+        })??;
+
+        self.send(Command::SetVolumes(index, volumes))
+    }
+
+    fn is_muted(&mut self, kind: StreamKind, index: u32) -> Result<bool, BackendError> {
+        self.with_node(kind, index, |state| state.muted)
+    }
+
+    fn set_mute(&mut self, kind: StreamKind, index: u32, mute: bool) -> Result<(), BackendError> {
+        self.with_node(kind, index, |state| state.muted = mute)?;
+        self.send(Command::SetMute(index, mute))
+    }
+
+    fn subscribe(&mut self, callback: Box<dyn Fn() + Send>) -> Result<(), BackendError> {
+        *self.callback.lock().unwrap() = Some(callback);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pipewire::properties::properties;
+
+    fn backend(volumes: Vec<f32>) -> (PipeWire, pipewire::channel::Receiver<Command>) {
+        let (commands, receiver) = pipewire::channel::channel();
+        let nodes = Nodes::default();
+        nodes.lock().unwrap().insert(
+            7,
+            NodeState {
+                kind: StreamKind::Application,
+                name: "firefox".to_string(),
+                volumes,
+                muted: false,
+            },
+        );
+        let pipewire = PipeWire {
+            nodes,
+            callback: Callback::default(),
+            commands,
+        };
+        (pipewire, receiver)
+    }
+
+    fn node_volumes(pipewire: &PipeWire) -> Vec<f32> {
+        pipewire.nodes.lock().unwrap()[&7].volumes.clone()
+    }
+
+    #[test]
+    fn percentages_are_the_cube_root_of_linear_volumes() {
+        assert!((to_percentage(0.125) - 0.5).abs() < 1e-6);
+        assert!((from_percentage(0.5) - 0.125).abs() < 1e-6);
+        assert_eq!(to_percentage(-1.0), 0.0);
+        assert_eq!(from_percentage(-1.0), 0.0);
+        for percentage in [0.0, 0.3, 1.0, 1.5] {
+            assert!((to_percentage(from_percentage(percentage)) - percentage).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn media_classes_map_to_stream_kinds() {
+        assert_eq!(
+            stream_kind("Stream/Output/Audio"),
+            Some(StreamKind::Application)
+        );
+        assert_eq!(
+            stream_kind("Stream/Input/Audio"),
+            Some(StreamKind::RecordingStream)
+        );
+        assert_eq!(stream_kind("Audio/Sink"), Some(StreamKind::OutputDevice));
+        assert_eq!(stream_kind("Audio/Source"), Some(StreamKind::InputDevice));
+        assert_eq!(stream_kind("Video/Source"), None);
+    }
+
+    #[test]
+    fn applications_are_named_by_binary_and_devices_by_description() {
+        let props = properties! {
+            "application.name" => "Firefox",
+            "application.process.binary" => "firefox",
+            "node.description" => "Built-in Audio",
+            "node.name" => "alsa_output.pci",
+        };
+        assert_eq!(
+            node_name(StreamKind::Application, props.dict()),
+            Some("firefox".to_string())
+        );
+        assert_eq!(
+            node_name(StreamKind::OutputDevice, props.dict()),
+            Some("Built-in Audio".to_string())
+        );
+
+        let props = properties! {
+            "application.name" => "Discord",
+        };
+        assert_eq!(
+            node_name(StreamKind::RecordingStream, props.dict()),
+            Some("Discord".to_string())
+        );
+        assert_eq!(node_name(StreamKind::InputDevice, props.dict()), None);
+    }
+
+    #[test]
+    fn props_survive_a_round_trip() {
+        let bytes = props_pod(vec![
+            Property::new(
+                spa::sys::SPA_PROP_channelVolumes,
+                Value::ValueArray(ValueArray::Float(vec![0.5, 0.25])),
+            ),
+            Property::new(spa::sys::SPA_PROP_mute, Value::Bool(true)),
+        ])
+        .unwrap();
+        let pod = Pod::from_bytes(&bytes).unwrap();
+        assert_eq!(parse_props(pod), (Some(vec![0.5, 0.25]), Some(true)));
+
+        let bytes = props_pod(vec![Property::new(
+            spa::sys::SPA_PROP_mute,
+            Value::Bool(false),
+        )])
+        .unwrap();
+        let pod = Pod::from_bytes(&bytes).unwrap();
+        assert_eq!(parse_props(pod), (None, Some(false)));
+    }
+
+    #[test]
+    fn set_volume_keeps_the_balance() {
+        let (mut pipewire, _receiver) = backend(vec![0.125, 0.015625]);
+        pipewire
+            .set_volume(StreamKind::Application, 7, 1.0)
+            .unwrap();
+        let volumes = node_volumes(&pipewire);
+        assert!((volumes[0] - 1.0).abs() < 1e-6);
+        assert!((volumes[1] - 0.125).abs() < 1e-6);
+    }
+
+    #[test]
+    fn set_volume_needs_the_channels_first() {
+        let (mut pipewire, _receiver) = backend(Vec::new());
+        assert!(matches!(
+            pipewire.set_volume(StreamKind::Application, 7, 0.5),
+            Err(BackendError::Operation(_))
+        ));
+        assert!(node_volumes(&pipewire).is_empty());
+    }
+
+    #[test]
+    fn set_volume_checks_the_kind() {
+        let (mut pipewire, _receiver) = backend(vec![0.125]);
+        assert!(matches!(
+            pipewire.set_volume(StreamKind::OutputDevice, 7, 0.5),
+            Err(BackendError::Operation(_))
+        ));
+    }
+}
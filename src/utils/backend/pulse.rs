@@ -0,0 +1,238 @@
+use std::thread;
+
+use libpulse_binding::{
+    context::{subscribe::InterestMaskSet, Context, FlagSet, State},
+    mainloop::standard::{IterateResult, Mainloop},
+    volume::{ChannelVolumes, Volume},
+};
+use pulsectl::{
+    controllers::{
+        types::ApplicationInfo, AppControl, DeviceControl, SinkController, SourceController,
+    },
+    ControllerError,
+};
+
+use super::{AudioBackend, BackendError, StreamInfo, StreamKind};
+
+impl From<ControllerError> for BackendError {
+    fn from(error: ControllerError) -> Self {
+        BackendError::Operation(error.to_string())
+    }
+}
+
+fn no_gain_stages() -> BackendError {
+    BackendError::Operation("PulseAudio has no gain stages".to_string())
+}
+
+// Loudest channel, the one a balance preserving change scales to the new volume
+fn to_percentage(volume: &ChannelVolumes) -> f64 {
+    volume.max().0 as f64 / Volume::NORMAL.0 as f64
+}
+
+fn from_percentage(volume: f64) -> Volume {
+    let raw = (volume.max(0.0) * Volume::NORMAL.0 as f64).round();
+    Volume((raw as u32).min(Volume::MAX.0))
+}
+
+// Playback and recording streams are both named after the binary of their application
+fn app_info(app: &ApplicationInfo) -> StreamInfo {
+    StreamInfo {
+        index: app.index,
+        name: match app.proplist.get("application.process.binary") {
+            Some(binary_name) => String::from_utf8_lossy(binary_name)
+                .trim_end_matches('\0')
+                .to_string(),
+            None => "".to_string(),
+        },
+    }
+}
+
+fn iterate(mainloop: &mut Mainloop) -> Result<(), BackendError> {
+    match mainloop.iterate(true) {
+        IterateResult::Success(_) => Ok(()),
+        IterateResult::Quit(_) => Err(BackendError::Connection("Mainloop quit".to_string())),
+        IterateResult::Err(e) => Err(BackendError::Connection(format!("{}", e))),
+    }
+}
+
+// Runs a connection of its own that only listens for changes, the controllers
+// only talk to the server while a request is pending
+fn watch(callback: Box<dyn Fn() + Send>) -> Result<(), BackendError> {
+    let mut mainloop = Mainloop::new()
+        .ok_or_else(|| BackendError::Connection("Failed to create mainloop".to_string()))?;
+    let mut context = Context::new(&mainloop, "MidiMixer Watcher")
+        .ok_or_else(|| BackendError::Connection("Failed to create context".to_string()))?;
+    context
+        .connect(None, FlagSet::NOFLAGS, None)
+        .map_err(|e| BackendError::Connection(format!("{}", e)))?;
+
+    loop {
+        iterate(&mut mainloop)?;
+        match context.get_state() {
+            State::Ready => break,
+            State::Failed | State::Terminated => {
+                return Err(BackendError::Connection("Context failed".to_string()))
+            }
+            _ => {}
+        }
+    }
+
+    context.set_subscribe_callback(Some(Box::new(move |_, _, _| callback())));
+    let _operation = context.subscribe(
+        InterestMaskSet::SINK
+            | InterestMaskSet::SINK_INPUT
+            | InterestMaskSet::SOURCE
+            | InterestMaskSet::SOURCE_OUTPUT,
+        |_| {},
+    );
+
+    loop {
+        iterate(&mut mainloop)?;
+    }
+}
+
+pub struct PulseAudio {
+    sinks: SinkController,
+    sources: SourceController,
+}
+
+impl PulseAudio {
+    pub fn new() -> Result<PulseAudio, BackendError> {
+        Ok(PulseAudio {
+            sinks: SinkController::create().map_err(|e| BackendError::Connection(e.to_string()))?,
+            sources: SourceController::create()
+                .map_err(|e| BackendError::Connection(e.to_string()))?,
+        })
+    }
+
+    fn get_channel_volumes(
+        &mut self,
+        kind: StreamKind,
+        index: u32,
+    ) -> Result<ChannelVolumes, BackendError> {
+        Ok(match kind {
+            StreamKind::Application => self.sinks.get_app_by_index(index)?.volume,
+            StreamKind::OutputDevice => self.sinks.get_device_by_index(index)?.volume,
+            StreamKind::InputDevice => self.sources.get_device_by_index(index)?.volume,
+            StreamKind::RecordingStream => self.sources.get_app_by_index(index)?.volume,
+            StreamKind::GainStage => return Err(no_gain_stages()),
+        })
+    }
+}
+
+impl AudioBackend for PulseAudio {
+    fn list_applications(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        let applications = self.sinks.list_applications()?;
+        Ok(applications.iter().map(app_info).collect())
+    }
+
+    fn list_devices(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        let devices = self.sinks.list_devices()?;
+        Ok(devices
+            .iter()
+            .map(|device| StreamInfo {
+                index: device.index,
+                name: device.description.clone().unwrap_or("".to_string()),
+            })
+            .collect())
+    }
+
+    fn list_sources(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        let sources = self.sources.list_devices()?;
+        Ok(sources
+            .iter()
+            .map(|source| StreamInfo {
+                index: source.index,
+                name: source.description.clone().unwrap_or("".to_string()),
+            })
+            .collect())
+    }
+
+    fn list_recording_streams(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        let recordings = self.sources.list_applications()?;
+        Ok(recordings.iter().map(app_info).collect())
+    }
+
+    fn list_gain_stages(&mut self) -> Result<Vec<StreamInfo>, BackendError> {
+        Ok(Vec::new())
+    }
+
+    fn get_volume(&mut self, kind: StreamKind, index: u32) -> Result<f64, BackendError> {
+        Ok(to_percentage(&self.get_channel_volumes(kind, index)?))
+    }
+
+    fn set_volume(
+        &mut self,
+        kind: StreamKind,
+        index: u32,
+        volume: f64,
+    ) -> Result<(), BackendError> {
+        let mut volumes = self.get_channel_volumes(kind, index)?;
+        // Keeps the balance, a stream that is all silent gets the same volume everywhere
+        volumes
+            .scale(from_percentage(volume))
+            .ok_or_else(|| BackendError::Operation(format!("Invalid volume {}", volume)))?;
+
+        let handler = match kind {
+            StreamKind::Application | StreamKind::OutputDevice => &mut self.sinks.handler,
+            StreamKind::InputDevice | StreamKind::RecordingStream => &mut self.sources.handler,
+            StreamKind::GainStage => return Err(no_gain_stages()),
+        };
+        let introspect = &mut handler.introspect;
+        let op = match kind {
+            StreamKind::Application => introspect.set_sink_input_volume(index, &volumes, None),
+            StreamKind::OutputDevice => introspect.set_sink_volume_by_index(index, &volumes, None),
+            StreamKind::InputDevice => introspect.set_source_volume_by_index(index, &volumes, None),
+            StreamKind::RecordingStream => {
+                introspect.set_source_output_volume(index, &volumes, None)
+            }
+            StreamKind::GainStage => return Err(no_gain_stages()),
+        };
+        handler
+            .wait_for_operation(op)
+            .map_err(|e| BackendError::Operation(e.to_string()))?;
+
+        Ok(())
+    }
+
+    fn is_muted(&mut self, kind: StreamKind, index: u32) -> Result<bool, BackendError> {
+        Ok(match kind {
+            StreamKind::Application => self.sinks.get_app_by_index(index)?.mute,
+            StreamKind::OutputDevice => self.sinks.get_device_by_index(index)?.mute,
+            StreamKind::InputDevice => self.sources.get_device_by_index(index)?.mute,
+            StreamKind::RecordingStream => self.sources.get_app_by_index(index)?.mute,
+            StreamKind::GainStage => return Err(no_gain_stages()),
+        })
+    }
+
+    fn set_mute(&mut self, kind: StreamKind, index: u32, mute: bool) -> Result<(), BackendError> {
+        match kind {
+            StreamKind::Application => {
+                self.sinks.set_app_mute(index, mute)?;
+            }
+            StreamKind::OutputDevice => self.sinks.set_device_mute_by_index(index, mute),
+            StreamKind::InputDevice => self.sources.set_device_mute_by_index(index, mute),
+            // set_app_mute of the SourceController mutes the source with that index instead
+            StreamKind::RecordingStream => {
+                let handler = &mut self.sources.handler;
+                let op = handler.introspect.set_source_output_mute(index, mute, None);
+                handler
+                    .wait_for_operation(op)
+                    .map_err(|e| BackendError::Operation(e.to_string()))?;
+            }
+            StreamKind::GainStage => return Err(no_gain_stages()),
+        }
+
+        Ok(())
+    }
+
+    fn subscribe(&mut self, callback: Box<dyn Fn() + Send>) -> Result<(), BackendError> {
+        thread::spawn(move || {
+            if let Err(e) = watch(callback) {
+                eprintln!("Stopped watching PulseAudio for changes: {}", e);
+            }
+        });
+
+        Ok(())
+    }
+}
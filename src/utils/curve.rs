@@ -0,0 +1,227 @@
+use serde::{Deserialize, Serialize};
+
+// How a fader position from 0.0 to 1.0 maps to a volume, 1.0 being 100%.
+//
+// Volumes are the percentages PulseAudio and PipeWire show, the amplitude they stand
+// for is their cube. Linear hands the position straight to the sound server like
+// faders always did, cubic and dB curves describe the amplitude.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Curve {
+    // Volume equals position, which is the perceptual scale of PulseAudio
+    #[default]
+    Linear,
+    // Amplitude follows the position, so the volume is its cube root and most of the
+    // travel is near the top
+    Cubic,
+    // Position spread evenly between two levels in dB, the bottom end is silence
+    Db {
+        min: f64,
+        max: f64,
+    },
+    // Breakpoints of [position, volume], interpolated linearly in between
+    Table(Vec<(f64, f64)>),
+}
+
+// Amplitude of a sound server volume is its cube, 20 dB per decade of amplitude
+const DB_PER_DECADE: f64 = 60.0;
+
+fn interpolate(x: f64, (x0, y0): (f64, f64), (x1, y1): (f64, f64)) -> f64 {
+    if x1 == x0 {
+        return y1;
+    }
+    y0 + (x - x0) * (y1 - y0) / (x1 - x0)
+}
+
+impl Curve {
+    pub fn to_volume(&self, position: f64) -> f64 {
+        let position = position.clamp(0.0, 1.0);
+
+        match self {
+            Curve::Linear => position,
+            Curve::Cubic => position.cbrt(),
+            Curve::Db { min, max } => {
+                if position == 0.0 {
+                    return 0.0;
+                }
+                let db = min + position * (max - min);
+                10f64.powf(db / DB_PER_DECADE)
+            }
+            Curve::Table(points) => {
+                let after = points.iter().position(|&(x, _)| x >= position);
+                match after {
+                    Some(0) => points[0].1,
+                    Some(i) => interpolate(position, points[i - 1], points[i]),
+                    None => points.last().map_or(position, |&(_, y)| y),
+                }
+            }
+        }
+    }
+
+    // Inverse of to_volume, where a motorized fader or LED ring has to go for a volume
+    pub fn to_position(&self, volume: f64) -> f64 {
+        let volume = volume.max(0.0);
+
+        let position = match self {
+            Curve::Linear => volume,
+            Curve::Cubic => volume.powi(3),
+            Curve::Db { min, max } => {
+                if volume == 0.0 {
+                    return 0.0;
+                }
+                let db = DB_PER_DECADE * volume.log10();
+                (db - min) / (max - min)
+            }
+            // Tables only ever go up, see validate
+            Curve::Table(points) => {
+                let after = points.iter().position(|&(_, y)| y >= volume);
+                match after {
+                    Some(0) => points[0].0,
+                    Some(i) => interpolate(
+                        volume,
+                        (points[i - 1].1, points[i - 1].0),
+                        (points[i].1, points[i].0),
+                    ),
+                    None => points.last().map_or(volume, |&(x, _)| x),
+                }
+            }
+        };
+        position.clamp(0.0, 1.0)
+    }
+
+    // Describes what is wrong with the parameters of the curve
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Curve::Linear | Curve::Cubic => Ok(()),
+            Curve::Db { min, max } if min >= max => {
+                Err(format!("dB range {} to {} is empty", min, max))
+            }
+            Curve::Db { .. } => Ok(()),
+            Curve::Table(points) if points.len() < 2 => {
+                Err("a table needs at least two points".to_string())
+            }
+            Curve::Table(points) => {
+                if points
+                    .iter()
+                    .any(|&(x, y)| !(0.0..=1.0).contains(&x) || y < 0.0)
+                {
+                    return Err(
+                        "table positions go from 0.0 to 1.0 and volumes can't be negative"
+                            .to_string(),
+                    );
+                }
+                if points
+                    .windows(2)
+                    .any(|pair| pair[0].0 >= pair[1].0 || pair[0].1 > pair[1].1)
+                {
+                    return Err(
+                        "table points must be sorted by position and never get quieter".to_string(),
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn linear_is_the_identity() {
+        for position in [0.0, 0.25, 0.5, 1.0] {
+            assert_eq!(Curve::Linear.to_volume(position), position);
+            assert_eq!(Curve::Linear.to_position(position), position);
+        }
+        assert_eq!(Curve::Linear.to_volume(1.5), 1.0);
+    }
+
+    #[test]
+    fn cubic_follows_amplitude() {
+        // Half the amplitude is about 79% on the sound server
+        assert_close(Curve::Cubic.to_volume(0.5), 0.5f64.cbrt());
+        assert_close(Curve::Cubic.to_volume(0.125), 0.5);
+        assert_close(Curve::Cubic.to_position(0.5), 0.125);
+    }
+
+    #[test]
+    fn db_range_spreads_decibels_evenly() {
+        let curve = Curve::Db {
+            min: -60.0,
+            max: 0.0,
+        };
+
+        assert_eq!(curve.to_volume(0.0), 0.0);
+        assert_close(curve.to_volume(1.0), 1.0);
+        // -30 dB
+        assert_close(curve.to_volume(0.5), 10f64.powf(-0.5));
+        assert_close(curve.to_position(10f64.powf(-0.5)), 0.5);
+        assert_eq!(curve.to_position(0.0), 0.0);
+        // Quieter than the bottom of the range still parks the fader at the bottom
+        assert_eq!(curve.to_position(0.001), 0.0);
+    }
+
+    #[test]
+    fn table_interpolates_between_points() {
+        let curve = Curve::Table(vec![(0.0, 0.0), (0.5, 0.8), (1.0, 1.0)]);
+
+        assert_close(curve.to_volume(0.25), 0.4);
+        assert_close(curve.to_volume(0.75), 0.9);
+        assert_close(curve.to_volume(1.0), 1.0);
+        assert_close(curve.to_position(0.4), 0.25);
+        assert_close(curve.to_position(0.9), 0.75);
+    }
+
+    #[test]
+    fn invalid_curves_are_rejected() {
+        assert!(Curve::Db {
+            min: 0.0,
+            max: -60.0
+        }
+        .validate()
+        .is_err());
+        assert!(Curve::Table(vec![(0.0, 0.0)]).validate().is_err());
+        assert!(Curve::Table(vec![(0.5, 0.0), (0.2, 1.0)])
+            .validate()
+            .is_err());
+        assert!(Curve::Table(vec![(0.0, 1.0), (1.0, 0.5)])
+            .validate()
+            .is_err());
+        assert!(Curve::Table(vec![(0.0, 0.0), (1.0, 1.5)])
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn curves_load_from_toml() {
+        #[derive(Deserialize)]
+        struct Config {
+            curve: Curve,
+        }
+        let curve = |toml: &str| toml::from_str::<Config>(toml).unwrap().curve;
+
+        assert_eq!(curve(r#"curve = "linear""#), Curve::Linear);
+        assert_eq!(curve(r#"curve = "cubic""#), Curve::Cubic);
+        assert_eq!(
+            curve("curve = { db = { min = -60, max = 0 } }"),
+            Curve::Db {
+                min: -60.0,
+                max: 0.0
+            }
+        );
+        assert_eq!(
+            curve("curve = { table = [[0.0, 0.0], [1.0, 1.0]] }"),
+            Curve::Table(vec![(0.0, 0.0), (1.0, 1.0)])
+        );
+    }
+}
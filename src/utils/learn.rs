@@ -0,0 +1,416 @@
+use std::{
+    error::Error,
+    fs,
+    io::{stdin, stdout, Write},
+    ops::RangeInclusive,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use toml_edit::{DocumentMut, Item, Table};
+
+use super::{
+    midi_controller::{Controller, MessageKind, MidiController, MidiMessage},
+    midi_mixer::MixerEvent,
+    profile::{Button, Encoder, EncoderMode, Fader, Profile},
+};
+
+// A control is done once it has been left alone for this long
+const QUIET_TIME: Duration = Duration::from_millis(1500);
+// A control that sends nothing for this long is skipped
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(10);
+const EVENT_QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug, PartialEq)]
+enum LearnedControl {
+    Button(Button),
+    Fader(Fader),
+    Encoder(Encoder),
+}
+
+// Values a knob turned both ways sends in each encoding, a few steps at a time and never 0
+const ENCODINGS: [(EncoderMode, [RangeInclusive<u16>; 2]); 3] = [
+    (EncoderMode::TwosComplement, [1..=15, 113..=127]),
+    (EncoderMode::SignMagnitude, [1..=15, 65..=79]),
+    (EncoderMode::BinaryOffset, [49..=63, 65..=79]),
+];
+
+fn relative_encoding(values: &[u16]) -> Option<EncoderMode> {
+    ENCODINGS
+        .iter()
+        .find(|(_, [up, down])| {
+            values.iter().all(|v| up.contains(v) || down.contains(v))
+                && values.iter().any(|v| up.contains(v))
+                && values.iter().any(|v| down.contains(v))
+        })
+        .map(|(encoding, _)| *encoding)
+}
+
+fn prompt(text: &str) -> Result<String, Box<dyn Error>> {
+    print!("{}", text);
+    stdout().flush()?;
+
+    let mut line = String::new();
+    stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+// Waits until a control sends something, then records that device until it goes quiet.
+// No messages at all after timeout.
+fn capture(
+    receiver: &Receiver<MixerEvent>,
+    timeout: Duration,
+) -> Result<(String, Vec<MidiMessage>), Box<dyn Error>> {
+    // Anything that arrived while the user was typing belongs to no control
+    while receiver.try_recv().is_ok() {}
+
+    let mut messages = Vec::new();
+    let mut device = String::new();
+    let deadline = Instant::now() + timeout;
+    while messages.is_empty() {
+        match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(MixerEvent::Midi(source, message)) => {
+                device = source;
+                messages.push(message);
+            }
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) => return Ok((device, messages)),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    while let Ok(event) = receiver.recv_timeout(QUIET_TIME) {
+        match event {
+            MixerEvent::Midi(source, message) if source == device => messages.push(message),
+            _ => {}
+        }
+    }
+
+    Ok((device, messages))
+}
+
+fn infer(device: String, messages: &[MidiMessage]) -> Option<LearnedControl> {
+    let first = messages.first()?;
+
+    // Prefer the 14-bit message assembled from the raw ones when there is one
+    let parameter = messages
+        .iter()
+        .find(|m| matches!(m, MidiMessage::Nrpn { .. } | MidiMessage::Rpn { .. }));
+    let paired = match first {
+        MidiMessage::ControlChange { control, .. } if *control < 32 => messages.iter().any(
+            |m| matches!(m, MidiMessage::ControlChange { control: lsb, .. } if *lsb == control + 32),
+        ),
+        _ => false,
+    };
+    let control = match (parameter, paired) {
+        (Some(parameter), _) => parameter,
+        (None, true) => messages
+            .iter()
+            .find(|m| matches!(m, MidiMessage::ControlChange14 { .. }))?,
+        (None, false) => first,
+    };
+
+    let values: Vec<u16> = messages
+        .iter()
+        .filter(|m| m.same_control(control))
+        .map(|m| m.value())
+        .collect();
+    let min = *values.iter().min()?;
+    let max = *values.iter().max()?;
+    let mut distinct = values.clone();
+    distinct.sort();
+    distinct.dedup();
+
+    let kind = control.kind();
+    let channel = control.channel();
+    let resolution = if control.is_high_resolution() { 14 } else { 7 };
+
+    if kind == MessageKind::ControlChange && !control.is_high_resolution() {
+        if let Some(encoding) = relative_encoding(&distinct) {
+            return Some(LearnedControl::Encoder(Encoder::new(
+                device,
+                kind,
+                channel,
+                control.control(),
+                encoding,
+            )));
+        }
+    }
+
+    match kind {
+        // Pads send Note On with varying velocity, any press counts
+        MessageKind::NoteOn | MessageKind::NoteOff => Some(LearnedControl::Button(Button::new(
+            device,
+            MessageKind::NoteOn,
+            channel,
+            control.control(),
+            None,
+        ))),
+        MessageKind::ProgramChange => Some(LearnedControl::Button(Button::new(
+            device,
+            kind,
+            channel,
+            control.control(),
+            None,
+        ))),
+        // A button toggles between two values at most, it triggers on the higher one
+        _ if distinct.len() <= 2 => Some(LearnedControl::Button(Button::new(
+            device,
+            kind,
+            channel,
+            control.control(),
+            Some(max),
+        ))),
+        _ => Some(LearnedControl::Fader(Fader::new(
+            device,
+            kind,
+            channel,
+            control.control(),
+            resolution,
+            min,
+            max,
+        ))),
+    }
+}
+
+fn to_item<T: Serialize>(control: &T) -> Result<Item, Box<dyn Error>> {
+    let document: DocumentMut = toml::to_string(control)?.parse()?;
+    Ok(Item::Table(document.as_table().clone()))
+}
+
+// Adds the learned controls to the config as it was read, every other table, value and
+// comment in it stays as the user wrote it
+fn add_to_config(
+    config: &str,
+    learned: &[(String, LearnedControl)],
+) -> Result<String, Box<dyn Error>> {
+    let mut document: DocumentMut = config.parse()?;
+
+    for (name, control) in learned {
+        let (table, item) = match control {
+            LearnedControl::Button(button) => ("buttons", to_item(button)?),
+            LearnedControl::Fader(fader) => ("faders", to_item(fader)?),
+            LearnedControl::Encoder(encoder) => ("encoders", to_item(encoder)?),
+        };
+        // Written as [controls.faders.<name>] even when there was no [controls.faders] yet
+        let mut implicit = Table::new();
+        implicit.set_implicit(true);
+        document["controls"]
+            .as_table_like_mut()
+            .ok_or("controls in the config is not a table")?
+            .entry(table)
+            .or_insert(Item::Table(implicit))
+            .as_table_like_mut()
+            .ok_or_else(|| format!("controls.{} in the config is not a table", table))?
+            .insert(name, item);
+    }
+
+    Ok(document.to_string())
+}
+
+// Asks for control names and records one control each, then adds them to the config file
+pub fn run(profile: &Profile, config: &str, config_path: &str) -> Result<(), Box<dyn Error>> {
+    let (sender, receiver) = mpsc::sync_channel(EVENT_QUEUE_SIZE);
+    let mut controllers = Vec::new();
+    for (device, config) in profile.get_devices() {
+        let mut controller = MidiController::new(device.clone(), sender.clone());
+        match (config.virtual_port(), config.input()) {
+            (Some(name), _) => controller.create_virtual(name)?,
+            (None, Some(input)) => controller.connect_input(input)?,
+            _ => {}
+        }
+        controllers.push(controller);
+    }
+
+    let mut learned = Vec::new();
+    loop {
+        let name = prompt("Name of the control to learn (empty to finish): ")?;
+        if name.is_empty() {
+            break;
+        }
+        if profile.has_control(&name) || learned.iter().any(|(learned, _)| *learned == name) {
+            println!("There already is a control named {}", name);
+            continue;
+        }
+
+        println!("Move the fader end to end, press the button or turn the knob both ways now");
+        let (device, messages) = capture(&receiver, CAPTURE_TIMEOUT)?;
+        match infer(device, &messages) {
+            Some(control) => {
+                println!("Learned {}: {:?}", name, control);
+                learned.push((name, control));
+                // Saved right away so an interrupted session keeps what it learned so far
+                fs::write(config_path, add_to_config(config, &learned)?)?;
+                println!("Saved {}", config_path);
+            }
+            None => println!("Nothing received for {}", name),
+        }
+    }
+
+    for controller in controllers.iter_mut() {
+        controller.close();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{midi_controller::MidiParser, profile::DEFAULT_DEVICE};
+
+    fn parse(bytes: &[[u8; 3]]) -> Vec<MidiMessage> {
+        let mut parser = MidiParser::default();
+        bytes.iter().flat_map(|bytes| parser.parse(bytes)).collect()
+    }
+
+    fn learn(bytes: &[[u8; 3]]) -> Option<LearnedControl> {
+        infer(DEFAULT_DEVICE.to_string(), &parse(bytes))
+    }
+
+    #[test]
+    fn silent_control_is_skipped() {
+        let (_sender, receiver) = mpsc::sync_channel(EVENT_QUEUE_SIZE);
+        let (device, messages) = capture(&receiver, Duration::from_millis(10)).unwrap();
+        assert!(messages.is_empty());
+        assert_eq!(infer(device, &messages), None);
+    }
+
+    #[test]
+    fn seven_bit_cc_becomes_a_fader() {
+        assert_eq!(
+            learn(&[[0xB0, 2, 0], [0xB0, 2, 40], [0xB0, 2, 90], [0xB0, 2, 127]]),
+            Some(LearnedControl::Fader(Fader::new(
+                DEFAULT_DEVICE.to_string(),
+                MessageKind::ControlChange,
+                0,
+                2,
+                7,
+                0,
+                127
+            )))
+        );
+    }
+
+    #[test]
+    fn msb_and_lsb_pair_becomes_a_high_resolution_fader() {
+        let bytes: Vec<[u8; 3]> = [0u16, 5000, 16383]
+            .iter()
+            .flat_map(|&value| {
+                [
+                    [0xB1, 7, (value >> 7) as u8],
+                    [0xB1, 39, (value & 0x7F) as u8],
+                ]
+            })
+            .collect();
+
+        assert_eq!(
+            learn(&bytes),
+            Some(LearnedControl::Fader(Fader::new(
+                DEFAULT_DEVICE.to_string(),
+                MessageKind::ControlChange,
+                1,
+                7,
+                14,
+                0,
+                16383
+            )))
+        );
+    }
+
+    #[test]
+    fn note_becomes_a_button_for_any_velocity() {
+        assert_eq!(
+            learn(&[[0x99, 36, 100], [0x99, 36, 0]]),
+            Some(LearnedControl::Button(Button::new(
+                DEFAULT_DEVICE.to_string(),
+                MessageKind::NoteOn,
+                9,
+                36,
+                None
+            )))
+        );
+    }
+
+    #[test]
+    fn relative_knob_becomes_an_encoder() {
+        let encoder = |encoding| {
+            Some(LearnedControl::Encoder(Encoder::new(
+                DEFAULT_DEVICE.to_string(),
+                MessageKind::ControlChange,
+                0,
+                16,
+                encoding,
+            )))
+        };
+
+        assert_eq!(
+            learn(&[
+                [0xB0, 16, 1],
+                [0xB0, 16, 2],
+                [0xB0, 16, 127],
+                [0xB0, 16, 126]
+            ]),
+            encoder(EncoderMode::TwosComplement)
+        );
+        assert_eq!(
+            learn(&[[0xB0, 16, 1], [0xB0, 16, 1], [0xB0, 16, 65]]),
+            encoder(EncoderMode::SignMagnitude)
+        );
+        assert_eq!(
+            learn(&[[0xB0, 16, 65], [0xB0, 16, 63], [0xB0, 16, 62]]),
+            encoder(EncoderMode::BinaryOffset)
+        );
+        // Press and release of a button is no knob
+        assert!(matches!(
+            learn(&[[0xB0, 16, 127], [0xB0, 16, 0]]),
+            Some(LearnedControl::Button(_))
+        ));
+    }
+
+    #[test]
+    fn learned_controls_keep_the_rest_of_the_config() {
+        let config = r#"# My nanoKONTROL2
+midi_controller_name = "nanoKONTROL2"
+
+[controls.buttons.button1]
+control = 48 # solo
+channel = 0
+
+[controls.faders]
+
+[groups.unused] # no controls yet
+volume_control = []
+mute = []
+
+[mapping]
+"#;
+        let learned = vec![
+            (
+                "fader1".to_string(),
+                learn(&[[0xB0, 0, 0], [0xB0, 0, 64], [0xB0, 0, 127]]).unwrap(),
+            ),
+            (
+                "knob1".to_string(),
+                learn(&[[0xB0, 16, 1], [0xB0, 16, 127]]).unwrap(),
+            ),
+        ];
+
+        let saved = add_to_config(config, &learned).unwrap();
+        for line in config.lines() {
+            assert!(saved.contains(line), "{} is gone", line);
+        }
+
+        let value: toml::Value = toml::from_str(&saved).unwrap();
+        assert_eq!(
+            value["controls"]["faders"]["fader1"]["max"].as_integer(),
+            Some(127)
+        );
+        assert_eq!(
+            value["controls"]["encoders"]["knob1"]["control"].as_integer(),
+            Some(16)
+        );
+        assert!(Profile::new(&toml::from_str(&saved).unwrap()).is_ok());
+    }
+}
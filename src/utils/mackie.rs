@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+
+use super::midi_controller::MidiMessage;
+
+// Mackie Control Universal mapping of a surface with 8 channel strips.
+// Faders are Pitch Bend on the strip's channel, everything else lives on channel 0.
+pub const STRIPS: u8 = 8;
+
+pub const REC_ARM_NOTE: u8 = 0x00;
+pub const SOLO_NOTE: u8 = 0x08;
+pub const MUTE_NOTE: u8 = 0x10;
+pub const SELECT_NOTE: u8 = 0x18;
+pub const VPOT_PUSH_NOTE: u8 = 0x20;
+pub const FADER_TOUCH_NOTE: u8 = 0x68;
+
+pub const VPOT_CONTROL: u8 = 0x10; // Relative, sign-magnitude, LED ring 0x20 above
+
+pub const FADER_MAX: u16 = 16383;
+pub const LCD_CELL_WIDTH: usize = 7;
+pub const LED_ON: u8 = 0x7F;
+pub const LED_OFF: u8 = 0x00;
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    #[default]
+    Generic,
+    Mackie,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StripControl {
+    RecArm,
+    Solo,
+    Mute,
+    Select,
+    VPotPush,
+    FaderTouch,
+}
+
+// Decodes a button or touch note into the strip (0-based) and control it belongs to,
+// together with whether it was pressed or released
+pub fn decode_note(message: &MidiMessage) -> Option<(u8, StripControl, bool)> {
+    let (note, pressed) = match *message {
+        MidiMessage::NoteOn {
+            channel: 0, note, ..
+        } => (note, true),
+        MidiMessage::NoteOff {
+            channel: 0, note, ..
+        } => (note, false),
+        _ => return None,
+    };
+
+    let (base, control) = match note {
+        0x00..=0x07 => (REC_ARM_NOTE, StripControl::RecArm),
+        0x08..=0x0F => (SOLO_NOTE, StripControl::Solo),
+        0x10..=0x17 => (MUTE_NOTE, StripControl::Mute),
+        0x18..=0x1F => (SELECT_NOTE, StripControl::Select),
+        0x20..=0x27 => (VPOT_PUSH_NOTE, StripControl::VPotPush),
+        0x68..=0x6F => (FADER_TOUCH_NOTE, StripControl::FaderTouch),
+        _ => return None,
+    };
+
+    Some((note - base, control, pressed))
+}
+
+// SysEx writing text to the upper (0) or lower (1) row of a strip's scribble strip.
+// The display only knows ASCII, anything else is shown as a placeholder.
+pub fn lcd_sysex(strip: u8, row: u8, text: &str) -> Vec<u8> {
+    let offset = row * (STRIPS * LCD_CELL_WIDTH as u8) + strip * LCD_CELL_WIDTH as u8;
+
+    let mut sysex = vec![0xF0, 0x00, 0x00, 0x66, 0x14, 0x12, offset];
+    sysex.extend(
+        text.chars()
+            .map(|c| match c {
+                ' '..='~' => c as u8,
+                '\u{2014}' => b'-',
+                _ => b'?',
+            })
+            .chain(std::iter::repeat(b' '))
+            .take(LCD_CELL_WIDTH),
+    );
+    sysex.push(0xF7);
+
+    sysex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_notes_decode_to_strip_and_control() {
+        assert_eq!(
+            decode_note(&MidiMessage::NoteOn {
+                channel: 0,
+                note: 0x12,
+                velocity: 0x7F
+            }),
+            Some((2, StripControl::Mute, true))
+        );
+        // Touch sensors release with Note On at velocity 0, which parses as Note Off
+        assert_eq!(
+            decode_note(&MidiMessage::NoteOff {
+                channel: 0,
+                note: 0x69,
+                velocity: 0
+            }),
+            Some((1, StripControl::FaderTouch, false))
+        );
+        assert_eq!(
+            decode_note(&MidiMessage::NoteOn {
+                channel: 1,
+                note: 0x12,
+                velocity: 0x7F
+            }),
+            None
+        );
+        assert_eq!(
+            decode_note(&MidiMessage::NoteOn {
+                channel: 0,
+                note: 0x30,
+                velocity: 0x7F
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn lcd_sysex_addresses_cells() {
+        assert_eq!(
+            lcd_sysex(0, 0, "Music"),
+            [
+                0xF0, 0x00, 0x00, 0x66, 0x14, 0x12, 0, b'M', b'u', b's', b'i', b'c', b' ', b' ',
+                0xF7
+            ]
+        );
+
+        // Strip 3 on the lower row starts 56 cells in, 3 cells of 7 to the right of that
+        let sysex = lcd_sysex(3, 1, "Spotify Premium");
+        assert_eq!(sysex[..7], [0xF0, 0x00, 0x00, 0x66, 0x14, 0x12, 56 + 3 * 7]);
+        assert_eq!(&sysex[7..14], b"Spotify");
+        assert_eq!(sysex.last(), Some(&0xF7));
+
+        assert_eq!(&lcd_sysex(7, 1, "\u{2014}Café")[7..14], b"-Caf?  ");
+    }
+}
@@ -1,84 +1,908 @@
-use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midir::os::unix::{VirtualInput, VirtualOutput};
+use midir::{Ignore, MidiIO, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
-use std::io::{stdin, stdout, Write};
-use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::sync::mpsc::SyncSender;
 
-#[derive(PartialEq, Eq, Clone, Copy)]
-pub struct MidiMessage {
-    pub channel: u8,
-    pub control: u8,
-    pub value: u8,
+use super::{midi_mixer::MixerEvent, profile::DeviceConfig};
+
+#[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    NoteOn,
+    NoteOff,
+    #[default]
+    #[serde(alias = "cc")]
+    ControlChange,
+    ProgramChange,
+    ChannelPressure,
+    PitchBend,
+    Nrpn,
+    Rpn,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MidiMessage {
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    ControlChange {
+        channel: u8,
+        control: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    PitchBend {
+        channel: u8,
+        value: u16,
+    }, // 14-bit, centered at 8192
+    // Derived by MidiParser from CC 0-31 paired with CC 32-63
+    ControlChange14 {
+        channel: u8,
+        control: u8,
+        value: u16,
+    },
+    Nrpn {
+        channel: u8,
+        parameter: u16,
+        value: u16,
+    },
+    Rpn {
+        channel: u8,
+        parameter: u16,
+        value: u16,
+    },
 }
 
 impl MidiMessage {
     fn new(message: &[u8]) -> Option<MidiMessage> {
-        if message.len() < 3 {
-            return None; // Not a complete MIDI message
-        }
-
-        let status = message[0];
+        let status = *message.first()?;
         let channel = status & 0x0F; // Get the channel (lower 4 bits)
         let message_type = status & 0xF0; // Get the message type
 
-        // Check if this is a Control Change message (0xB0 to 0xBF)
-        if message_type == 0xB0 {
-            let control = message[1];
-            let value = message[2];
+        match (message_type, &message[1..]) {
+            // A Note On with velocity 0 is a Note Off by convention
+            (0x80, &[note, velocity, ..]) | (0x90, &[note, velocity @ 0, ..]) => {
+                Some(MidiMessage::NoteOff {
+                    channel,
+                    note,
+                    velocity,
+                })
+            }
+            (0x90, &[note, velocity, ..]) => Some(MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            }),
+            (0xB0, &[control, value, ..]) => Some(MidiMessage::ControlChange {
+                channel,
+                control,
+                value,
+            }),
+            (0xC0, &[program, ..]) => Some(MidiMessage::ProgramChange { channel, program }),
+            (0xD0, &[pressure, ..]) => Some(MidiMessage::ChannelPressure { channel, pressure }),
+            (0xE0, &[lsb, msb, ..]) => Some(MidiMessage::PitchBend {
+                channel,
+                value: (msb as u16) << 7 | lsb as u16,
+            }),
+            _ => None, // Not a complete or supported channel message
+        }
+    }
+
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            MidiMessage::NoteOn { .. } => MessageKind::NoteOn,
+            MidiMessage::NoteOff { .. } => MessageKind::NoteOff,
+            MidiMessage::ControlChange { .. } | MidiMessage::ControlChange14 { .. } => {
+                MessageKind::ControlChange
+            }
+            MidiMessage::ProgramChange { .. } => MessageKind::ProgramChange,
+            MidiMessage::ChannelPressure { .. } => MessageKind::ChannelPressure,
+            MidiMessage::PitchBend { .. } => MessageKind::PitchBend,
+            MidiMessage::Nrpn { .. } => MessageKind::Nrpn,
+            MidiMessage::Rpn { .. } => MessageKind::Rpn,
+        }
+    }
+
+    pub fn channel(&self) -> u8 {
+        match *self {
+            MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. }
+            | MidiMessage::ChannelPressure { channel, .. }
+            | MidiMessage::PitchBend { channel, .. }
+            | MidiMessage::ControlChange14 { channel, .. }
+            | MidiMessage::Nrpn { channel, .. }
+            | MidiMessage::Rpn { channel, .. } => channel,
+        }
+    }
+
+    // Note, controller, program or parameter number, 0 for messages that only carry a value
+    pub fn control(&self) -> u16 {
+        match *self {
+            MidiMessage::NoteOn { note, .. } | MidiMessage::NoteOff { note, .. } => note as u16,
+            MidiMessage::ControlChange { control, .. }
+            | MidiMessage::ControlChange14 { control, .. } => control as u16,
+            MidiMessage::ProgramChange { program, .. } => program as u16,
+            MidiMessage::ChannelPressure { .. } | MidiMessage::PitchBend { .. } => 0,
+            MidiMessage::Nrpn { parameter, .. } | MidiMessage::Rpn { parameter, .. } => parameter,
+        }
+    }
+
+    // Program changes carry no value, they act like a full press
+    pub fn value(&self) -> u16 {
+        match *self {
+            MidiMessage::NoteOn { velocity, .. } | MidiMessage::NoteOff { velocity, .. } => {
+                velocity as u16
+            }
+            MidiMessage::ControlChange { value, .. } => value as u16,
+            MidiMessage::ProgramChange { .. } => 127,
+            MidiMessage::ChannelPressure { pressure, .. } => pressure as u16,
+            MidiMessage::PitchBend { value, .. }
+            | MidiMessage::ControlChange14 { value, .. }
+            | MidiMessage::Nrpn { value, .. }
+            | MidiMessage::Rpn { value, .. } => value,
+        }
+    }
+
+    // Raw bytes of every MIDI message needed to send this one
+    pub fn to_bytes(self) -> Vec<Vec<u8>> {
+        let cc = |channel: u8, control: u8, value: u8| vec![0xB0 | channel, control, value];
 
-            Some(MidiMessage {
+        match self {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => vec![vec![0x90 | channel, note, velocity]],
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => vec![vec![0x80 | channel, note, velocity]],
+            MidiMessage::ControlChange {
                 channel,
                 control,
                 value,
-            })
-        } else {
-            None // Not a Control Change message
+            } => vec![cc(channel, control, value)],
+            MidiMessage::ProgramChange { channel, program } => vec![vec![0xC0 | channel, program]],
+            MidiMessage::ChannelPressure { channel, pressure } => {
+                vec![vec![0xD0 | channel, pressure]]
+            }
+            MidiMessage::PitchBend { channel, value } => {
+                vec![vec![
+                    0xE0 | channel,
+                    (value & 0x7F) as u8,
+                    (value >> 7) as u8,
+                ]]
+            }
+            MidiMessage::ControlChange14 {
+                channel,
+                control,
+                value,
+            } => vec![
+                cc(channel, control, (value >> 7) as u8),
+                cc(channel, control + 32, (value & 0x7F) as u8),
+            ],
+            MidiMessage::Nrpn {
+                channel,
+                parameter,
+                value,
+            }
+            | MidiMessage::Rpn {
+                channel,
+                parameter,
+                value,
+            } => {
+                let (msb, lsb) = match self {
+                    MidiMessage::Nrpn { .. } => (99, 98),
+                    _ => (101, 100),
+                };
+
+                vec![
+                    cc(channel, msb, (parameter >> 7) as u8),
+                    cc(channel, lsb, (parameter & 0x7F) as u8),
+                    cc(channel, 6, (value >> 7) as u8),
+                    cc(channel, 38, (value & 0x7F) as u8),
+                ]
+            }
+        }
+    }
+
+    pub fn is_high_resolution(&self) -> bool {
+        matches!(
+            self,
+            MidiMessage::PitchBend { .. }
+                | MidiMessage::ControlChange14 { .. }
+                | MidiMessage::Nrpn { .. }
+                | MidiMessage::Rpn { .. }
+        )
+    }
+
+    pub fn same_control(&self, other: &MidiMessage) -> bool {
+        self.kind() == other.kind()
+            && self.is_high_resolution() == other.is_high_resolution()
+            && self.channel() == other.channel()
+            && self.control() == other.control()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Parameter {
+    None,
+    Nrpn,
+    Rpn,
+}
+
+#[derive(Clone, Copy)]
+struct ChannelState {
+    msb: [u8; 32],
+    lsb: [u8; 32],
+    nrpn: (u8, u8),
+    rpn: (u8, u8),
+    selected: Parameter,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        ChannelState {
+            msb: [0; 32],
+            lsb: [0; 32],
+            nrpn: (0, 0),
+            rpn: (127, 127), // The RPN null parameter
+            selected: Parameter::None,
+        }
+    }
+}
+
+// Keeps track of MSB/LSB pairs and (N)RPN selection per channel. Every raw message
+// is passed on, followed by the 14-bit message it completes, if any.
+#[derive(Default)]
+pub struct MidiParser {
+    channels: [ChannelState; 16],
+}
+
+impl MidiParser {
+    pub fn parse(&mut self, message: &[u8]) -> Vec<MidiMessage> {
+        let Some(msg) = MidiMessage::new(message) else {
+            return Vec::new();
+        };
+
+        let mut messages = vec![msg];
+        if let MidiMessage::ControlChange {
+            channel,
+            control,
+            value,
+        } = msg
+        {
+            messages.extend(self.control_change(channel, control, value));
+        }
+
+        messages
+    }
+
+    fn control_change(&mut self, channel: u8, control: u8, value: u8) -> Option<MidiMessage> {
+        let state = &mut self.channels[channel as usize];
+
+        match control {
+            99 => {
+                state.nrpn.0 = value;
+                state.selected = Parameter::Nrpn;
+            }
+            98 => {
+                state.nrpn.1 = value;
+                state.selected = Parameter::Nrpn;
+            }
+            101 => {
+                state.rpn.0 = value;
+                state.selected = Parameter::Rpn;
+            }
+            100 => {
+                state.rpn.1 = value;
+                state.selected = Parameter::Rpn;
+            }
+            _ => {}
+        }
+
+        let (msb_control, high_resolution) = match control {
+            // Like MIDI 1.0 says, a new MSB resets its LSB, so a jump from 64/127 to 65/0
+            // never passes through 65/127. Data entry on CC 6 included.
+            0..=31 => {
+                state.msb[control as usize] = value;
+                state.lsb[control as usize] = 0;
+                (control, true)
+            }
+            32..=63 => {
+                state.lsb[(control - 32) as usize] = value;
+                (control - 32, true)
+            }
+            _ => (control, false),
+        };
+
+        if !high_resolution {
+            return None;
+        }
+
+        let value =
+            (state.msb[msb_control as usize] as u16) << 7 | state.lsb[msb_control as usize] as u16;
+
+        // CC 6 and 38 are the data entry pair for the selected (N)RPN
+        match (msb_control, state.selected) {
+            (6, Parameter::Nrpn) => Some(MidiMessage::Nrpn {
+                channel,
+                parameter: (state.nrpn.0 as u16) << 7 | state.nrpn.1 as u16,
+                value,
+            }),
+            (6, Parameter::Rpn) if state.rpn != (127, 127) => Some(MidiMessage::Rpn {
+                channel,
+                parameter: (state.rpn.0 as u16) << 7 | state.rpn.1 as u16,
+                value,
+            }),
+            _ => Some(MidiMessage::ControlChange14 {
+                channel,
+                control: msb_control,
+                value,
+            }),
+        }
+    }
+}
+
+// One way of recognizing a port among the ones the system currently offers
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum PortMatcher {
+    Exact(String), // Ignores the ALSA client:port suffix, e.g. " 28:0"
+    Contains { contains: String },
+    Regex { regex: String },
+    Index { index: usize },
+}
+
+// Drops the " 28:0" ALSA appends, its numbers change between boots and USB ports
+fn strip_client_id(name: &str) -> &str {
+    match name.rsplit_once(' ') {
+        Some((base, id))
+            if id.split_once(':').is_some_and(|(client, port)| {
+                !client.is_empty()
+                    && !port.is_empty()
+                    && client
+                        .chars()
+                        .chain(port.chars())
+                        .all(|c| c.is_ascii_digit())
+            }) =>
+        {
+            base
+        }
+        _ => name,
+    }
+}
+
+impl PortMatcher {
+    pub fn validate(&self) -> Result<(), regex::Error> {
+        if let PortMatcher::Regex { regex } = self {
+            Regex::new(regex)?;
+        }
+
+        Ok(())
+    }
+
+    fn find(&self, port_names: &[String]) -> Option<usize> {
+        match self {
+            PortMatcher::Exact(name) => port_names
+                .iter()
+                .position(|port| strip_client_id(port) == strip_client_id(name)),
+            PortMatcher::Contains { contains } => {
+                port_names.iter().position(|port| port.contains(contains))
+            }
+            PortMatcher::Regex { regex } => {
+                let regex = Regex::new(regex).ok()?;
+                port_names.iter().position(|port| regex.is_match(port))
+            }
+            PortMatcher::Index { index } => (*index < port_names.len()).then_some(*index),
+        }
+    }
+}
+
+impl fmt::Display for PortMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortMatcher::Exact(name) => write!(f, "\"{}\"", name),
+            PortMatcher::Contains { contains } => write!(f, "containing \"{}\"", contains),
+            PortMatcher::Regex { regex } => write!(f, "matching /{}/", regex),
+            PortMatcher::Index { index } => write!(f, "#{}", index),
         }
     }
 }
 
-pub trait MidiCallback: Send + 'static {
-    fn handle_midi_message(&mut self, message: MidiMessage);
+// A single matcher or a list of them, tried in order until one finds a port
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum PortSelector {
+    Single(PortMatcher),
+    Fallback(Vec<PortMatcher>),
+}
+
+impl PortSelector {
+    pub fn matchers(&self) -> &[PortMatcher] {
+        match self {
+            PortSelector::Single(matcher) => std::slice::from_ref(matcher),
+            PortSelector::Fallback(matchers) => matchers,
+        }
+    }
+
+    pub fn find(&self, port_names: &[String]) -> Option<usize> {
+        self.matchers()
+            .iter()
+            .find_map(|matcher| matcher.find(port_names))
+    }
+}
+
+impl fmt::Display for PortSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let matchers: Vec<String> = self.matchers().iter().map(|m| m.to_string()).collect();
+        write!(f, "{}", matchers.join(" or "))
+    }
+}
+
+// What the mixer needs from a connected controller, tests stand in a fake for it
+pub trait Controller {
+    // Silently does nothing when there is no output port to send to
+    fn send(&mut self, message: &MidiMessage) -> Result<(), Box<dyn Error>>;
+    fn send_sysex(&mut self, sysex: &[u8]) -> Result<(), Box<dyn Error>>;
+    // Returns true when the connection state changed
+    fn supervise(&mut self) -> bool;
+    fn is_connected(&self) -> bool;
+    fn close(&mut self);
+}
+
+pub struct MidiController {
+    // Device name from the profile, attached to every event coming from this controller
+    device: String,
+    input_connection: Option<MidiInputConnection<MidiParser>>,
+    output_connection: Option<MidiOutputConnection>,
+    // Ports asked for, kept to reconnect after the controller was unplugged
+    input_port: Option<PortSelector>,
+    output_port: Option<PortSelector>,
+    // Opened once for supervising, every new client is announced to all other ALSA
+    // sequencer clients
+    input_watcher: Option<MidiInput>,
+    output_watcher: Option<MidiOutput>,
+    sender: SyncSender<MixerEvent>,
 }
 
-pub struct MidiController<T: MidiCallback> {
-    input_connection: Option<MidiInputConnection<()>>,
-    callback_handler: Arc<Mutex<T>>,
+fn port_available(io: &impl MidiIO, port: &PortSelector) -> bool {
+    let port_names: Vec<String> = io
+        .ports()
+        .iter()
+        .filter_map(|p| io.port_name(p).ok())
+        .collect();
+    port.find(&port_names).is_some()
 }
 
-impl<T: MidiCallback> MidiController<T> {
-    pub fn new(callback_handler: Arc<Mutex<T>>) -> MidiController<T> {
+impl MidiController {
+    pub fn new(device: String, sender: SyncSender<MixerEvent>) -> MidiController {
         MidiController {
+            device,
             input_connection: None,
-            callback_handler,
+            output_connection: None,
+            input_port: None,
+            output_port: None,
+            input_watcher: None,
+            output_watcher: None,
+            sender,
         }
     }
 
-    pub fn connect_input(&mut self, port_name: &str) -> Result<(), Box<dyn Error>> {
+    // Connects the ports of a device from the profile. A missing controller is only
+    // reported, the supervisor picks it up once it is plugged in.
+    pub fn open(
+        device: &str,
+        config: &DeviceConfig,
+        sender: SyncSender<MixerEvent>,
+    ) -> Result<MidiController, Box<dyn Error>> {
+        let mut controller = MidiController::new(device.to_string(), sender);
+        match (config.virtual_port(), config.input(), config.output()) {
+            (Some(name), _, _) => controller.create_virtual(name)?,
+            (None, Some(input), Some(output)) => {
+                if let Err(e) = controller.connect(input, output) {
+                    eprintln!("{}, waiting for MIDI controller {}", e, device);
+                }
+            }
+            _ => {}
+        }
+        Ok(controller)
+    }
+
+    pub fn connect_input(&mut self, port: &PortSelector) -> Result<(), Box<dyn Error>> {
+        self.input_port = Some(port.clone());
+
         let mut midi_in = MidiInput::new("MidiController Input")?;
         midi_in.ignore(Ignore::None); // To avoid ignoring MIDI events
 
         let in_ports = midi_in.ports();
-        let in_port = in_ports
+        let port_names: Vec<String> = in_ports
             .iter()
-            .find(|p| midi_in.port_name(p).map_or(false, |name| name == port_name))
-            .ok_or_else(|| format!("No input port found with name: {}", port_name))?;
-
-        // Clone the Arc for use in the closure
-        let callback_handler = Arc::clone(&self.callback_handler);
+            .map(|p| midi_in.port_name(p).unwrap_or_default())
+            .collect();
+        let in_port = &in_ports[port.find(&port_names).ok_or_else(|| {
+            format!(
+                "No input port found {}, available ports: {:?}",
+                port, port_names
+            )
+        })?];
 
         let conn_in = midi_in.connect(
             in_port,
             "Midi Input Connection",
-            move |_, message, _| {
-                if let Some(msg) = MidiMessage::new(message) {
-                    callback_handler.lock().unwrap().handle_midi_message(msg);
-                }
-            },
-            (),
+            self.input_callback(),
+            MidiParser::default(),
         )?;
 
         self.input_connection = Some(conn_in);
 
         Ok(())
     }
+
+    fn input_callback(&self) -> impl FnMut(u64, &[u8], &mut MidiParser) + Send + 'static {
+        // Clone the sender and device name for use in the closure
+        let sender = self.sender.clone();
+        let device = self.device.clone();
+
+        move |_, message, parser| {
+            for msg in parser.parse(message) {
+                // Blocks while the queue is full instead of dropping the event,
+                // only fails once the mixer is gone
+                let _ = sender.send(MixerEvent::Midi(device.clone(), msg));
+            }
+        }
+    }
+
+    // Creates an input port other software can send to and an output port with the
+    // same name carrying the feedback, instead of connecting to hardware
+    pub fn create_virtual(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        let mut midi_in = MidiInput::new("MidiController Input")?;
+        midi_in.ignore(Ignore::None);
+
+        let conn_in = midi_in
+            .create_virtual(name, self.input_callback(), MidiParser::default())
+            .map_err(|e| format!("Failed to create virtual input {}: {}", name, e))?;
+        self.input_connection = Some(conn_in);
+
+        let midi_out = MidiOutput::new("MidiController Output")?;
+        let conn_out = midi_out
+            .create_virtual(name)
+            .map_err(|e| format!("Failed to create virtual output {}: {}", name, e))?;
+        self.output_connection = Some(conn_out);
+
+        Ok(())
+    }
+
+    // Feedback is optional, so a missing output port is only reported
+    pub fn connect(
+        &mut self,
+        input_port: &PortSelector,
+        output_port: &PortSelector,
+    ) -> Result<(), Box<dyn Error>> {
+        self.output_port = Some(output_port.clone());
+        self.connect_input(input_port)?;
+
+        if let Err(e) = self.connect_output(output_port) {
+            eprintln!("MIDI feedback disabled: {}", e);
+        }
+
+        Ok(())
+    }
+
+    pub fn connect_output(&mut self, port: &PortSelector) -> Result<(), Box<dyn Error>> {
+        self.output_port = Some(port.clone());
+
+        let midi_out = MidiOutput::new("MidiController Output")?;
+
+        let out_ports = midi_out.ports();
+        let port_names: Vec<String> = out_ports
+            .iter()
+            .map(|p| midi_out.port_name(p).unwrap_or_default())
+            .collect();
+        let out_port = &out_ports[port.find(&port_names).ok_or_else(|| {
+            format!(
+                "No output port found {}, available ports: {:?}",
+                port, port_names
+            )
+        })?];
+
+        let conn_out = midi_out.connect(out_port, "Midi Output Connection")?;
+
+        self.output_connection = Some(conn_out);
+
+        Ok(())
+    }
+}
+
+impl Controller for MidiController {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), Box<dyn Error>> {
+        if let Some(connection) = self.output_connection.as_mut() {
+            for bytes in message.to_bytes() {
+                connection.send(&bytes)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send_sysex(&mut self, sysex: &[u8]) -> Result<(), Box<dyn Error>> {
+        if let Some(connection) = self.output_connection.as_mut() {
+            connection.send(sysex)?;
+        }
+
+        Ok(())
+    }
+
+    // Drops the connections once the input port disappears and brings them back when it
+    // shows up again, an output port that was missing is retried while the input is
+    // connected.
+    fn supervise(&mut self) -> bool {
+        let Some(input_port) = self.input_port.clone() else {
+            return false;
+        };
+        if self.input_watcher.is_none() {
+            self.input_watcher = MidiInput::new("MidiController Watcher").ok();
+        }
+        let available = self
+            .input_watcher
+            .as_ref()
+            .is_some_and(|watcher| port_available(watcher, &input_port));
+
+        if self.is_connected() && !available {
+            self.close();
+            return true;
+        }
+
+        if !self.is_connected() && available {
+            let connected = match self.output_port.clone() {
+                Some(output_port) => self.connect(&input_port, &output_port),
+                None => self.connect_input(&input_port),
+            };
+            return connected.is_ok();
+        }
+
+        if self.is_connected() && self.output_connection.is_none() {
+            let Some(output_port) = self.output_port.clone() else {
+                return false;
+            };
+            if self.output_watcher.is_none() {
+                self.output_watcher = MidiOutput::new("MidiController Watcher").ok();
+            }
+            let available = self
+                .output_watcher
+                .as_ref()
+                .is_some_and(|watcher| port_available(watcher, &output_port));
+            return available && self.connect_output(&output_port).is_ok();
+        }
+
+        false
+    }
+
+    fn is_connected(&self) -> bool {
+        self.input_connection.is_some()
+    }
+
+    fn close(&mut self) {
+        if let Some(connection) = self.input_connection.take() {
+            connection.close();
+        }
+
+        if let Some(connection) = self.output_connection.take() {
+            connection.close();
+        }
+    }
+}
+
+// Stands in for a connected controller in tests, raw bytes go through the parser and
+// into the mixer queue just like the input callback does it
+#[cfg(test)]
+pub struct FakeMidiSource {
+    device: String,
+    parser: MidiParser,
+    sender: SyncSender<MixerEvent>,
+}
+
+#[cfg(test)]
+impl FakeMidiSource {
+    pub fn new(device: &str, sender: SyncSender<MixerEvent>) -> FakeMidiSource {
+        FakeMidiSource {
+            device: device.to_string(),
+            parser: MidiParser::default(),
+            sender,
+        }
+    }
+
+    pub fn send(&mut self, bytes: &[u8]) {
+        for message in self.parser.parse(bytes) {
+            self.sender
+                .send(MixerEvent::Midi(self.device.clone(), message))
+                .unwrap();
+        }
+    }
+}
+
+// Everything a FakeController was asked to send, in order
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sent {
+    Message(MidiMessage),
+    SysEx(Vec<u8>),
+}
+
+// Shared by all fake controllers of a mixer, the device name tells them apart
+#[cfg(test)]
+pub type SentLog = std::rc::Rc<std::cell::RefCell<Vec<(String, Sent)>>>;
+
+// Controller without any ports, records what it was sent for the mixer tests
+#[cfg(test)]
+pub struct FakeController {
+    device: String,
+    sent: SentLog,
+}
+
+#[cfg(test)]
+impl FakeController {
+    pub fn new(device: &str, sent: SentLog) -> FakeController {
+        FakeController {
+            device: device.to_string(),
+            sent,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Controller for FakeController {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), Box<dyn Error>> {
+        self.sent
+            .borrow_mut()
+            .push((self.device.clone(), Sent::Message(*message)));
+        Ok(())
+    }
+
+    fn send_sysex(&mut self, sysex: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.sent
+            .borrow_mut()
+            .push((self.device.clone(), Sent::SysEx(sysex.to_vec())));
+        Ok(())
+    }
+
+    fn supervise(&mut self) -> bool {
+        false
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn close(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_on_without_velocity_is_note_off() {
+        assert_eq!(
+            MidiMessage::new(&[0x91, 60, 0]),
+            Some(MidiMessage::NoteOff {
+                channel: 1,
+                note: 60,
+                velocity: 0
+            })
+        );
+    }
+
+    #[test]
+    fn parser_pairs_msb_and_lsb() {
+        let mut parser = MidiParser::default();
+        parser.parse(&[0xB0, 7, 0x40]);
+
+        let messages = parser.parse(&[0xB0, 39, 0x01]);
+        assert_eq!(
+            messages.last(),
+            Some(&MidiMessage::ControlChange14 {
+                channel: 0,
+                control: 7,
+                value: (0x40 << 7) | 0x01
+            })
+        );
+    }
+
+    #[test]
+    fn new_msb_resets_the_lsb() {
+        let mut parser = MidiParser::default();
+        parser.parse(&[0xB0, 7, 64]);
+        parser.parse(&[0xB0, 39, 127]);
+
+        assert_eq!(
+            parser.parse(&[0xB0, 7, 65]).last(),
+            Some(&MidiMessage::ControlChange14 {
+                channel: 0,
+                control: 7,
+                value: 65 << 7
+            })
+        );
+
+        parser.parse(&[0xB0, 99, 1]);
+        parser.parse(&[0xB0, 98, 2]);
+        parser.parse(&[0xB0, 38, 100]);
+        assert_eq!(
+            parser.parse(&[0xB0, 6, 3]).last(),
+            Some(&MidiMessage::Nrpn {
+                channel: 0,
+                parameter: (1 << 7) | 2,
+                value: 3 << 7
+            })
+        );
+    }
+
+    #[test]
+    fn parser_assembles_nrpn() {
+        let mut parser = MidiParser::default();
+        let messages: Vec<MidiMessage> =
+            [[0xB2, 99, 1], [0xB2, 98, 2], [0xB2, 6, 3], [0xB2, 38, 4]]
+                .iter()
+                .flat_map(|bytes| parser.parse(bytes))
+                .collect();
+
+        assert!(messages.contains(&MidiMessage::Nrpn {
+            channel: 2,
+            parameter: (1 << 7) | 2,
+            value: (3 << 7) | 4
+        }));
+    }
+
+    #[test]
+    fn high_resolution_messages_round_trip() {
+        let message = MidiMessage::Nrpn {
+            channel: 3,
+            parameter: 300,
+            value: 9000,
+        };
+        let mut parser = MidiParser::default();
+        let parsed: Vec<MidiMessage> = message
+            .to_bytes()
+            .iter()
+            .flat_map(|bytes| parser.parse(bytes))
+            .collect();
+
+        assert_eq!(parsed.last(), Some(&message));
+    }
+
+    #[test]
+    fn port_selector_falls_back_in_order() {
+        let ports = vec![
+            "Midi Through:Midi Through Port-0 14:0".to_string(),
+            "nanoKONTROL2:nanoKONTROL2 nanoKONTROL2 _ CTR 28:0".to_string(),
+        ];
+        let selector = PortSelector::Fallback(vec![
+            PortMatcher::Contains {
+                contains: "X-Touch".to_string(),
+            },
+            PortMatcher::Exact("nanoKONTROL2:nanoKONTROL2 nanoKONTROL2 _ CTR 24:0".to_string()),
+        ]);
+
+        assert_eq!(selector.find(&ports), Some(1));
+    }
 }
@@ -1,170 +1,1288 @@
 use std::{
     cell::RefCell,
+    collections::{HashMap, HashSet},
     error::Error,
+    net::SocketAddr,
     rc::Rc,
-    sync::{Arc, Mutex},
-};
-
-use pulsectl::{
-    controllers::{AppControl, DeviceControl, SinkController},
-    ControllerError,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+    time::{Duration, Instant},
 };
 
 use super::{
-    midi_controller::{MidiCallback, MidiController, MidiMessage},
-    profile::Profile,
-    volume_control::{self, Application, OutputDevice, VolumeControl},
+    backend::{AudioBackend, BackendError, StreamKind},
+    mackie::{self, Protocol, StripControl},
+    midi_controller::{Controller, MessageKind, MidiController, MidiMessage},
+    osc::{self, OscArg, OscMessage, OscServer},
+    profile::{DeviceConfig, Profile, Takeover},
+    volume_control::{
+        Application, GainStage, InputDevice, Limited, OutputDevice, RecordingStream, VolumeControl,
+    },
 };
 
-struct MidiHandler {
-    previous_message: MidiMessage,
-    last_message: MidiMessage,
-}
-
-impl MidiHandler {
-    pub fn new() -> MidiHandler {
-        MidiHandler {
-            last_message: MidiMessage {
-                channel: 0,
-                control: 0,
-                value: 0,
-            },
-            previous_message: MidiMessage {
-                channel: 0,
-                control: 0,
-                value: 0,
-            },
-        }
-    }
-
-    pub fn get_last_message(&mut self) -> MidiMessage {
-        self.previous_message = self.last_message;
-        self.last_message
-    }
+// Enough room for several seconds of every fader on a controller moving at once
+const EVENT_QUEUE_SIZE: usize = 1024;
+// How often controllers are checked for being unplugged or plugged back in, busy or not
+const SUPERVISE_INTERVAL: Duration = Duration::from_millis(500);
 
-    pub fn is_available(&self) -> bool {
-        if self.previous_message != self.last_message {
-            return true;
+// Operations on a single stream fail whenever it goes away in between, like a browser
+// tab closing. Those are logged and skipped, only a lost sound server is passed on.
+fn recover<T>(result: Result<T, BackendError>) -> Result<Option<T>, BackendError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(BackendError::Connection(e)) => Err(BackendError::Connection(e)),
+        Err(e) => {
+            eprintln!("{}", e);
+            Ok(None)
         }
-
-        false
     }
 }
 
-impl MidiCallback for MidiHandler {
-    fn handle_midi_message(&mut self, message: MidiMessage) {
-        self.last_message = message;
-    }
+pub enum MixerEvent {
+    // Device name + message
+    Midi(String, MidiMessage),
+    // Sender + message
+    Osc(SocketAddr, OscMessage),
+    // A stream or device changed on the audio backend
+    AudioChanged,
+    Shutdown,
 }
 
 pub struct MidiMixer {
-    midi_handler: Arc<Mutex<MidiHandler>>,
-    controller: MidiController<MidiHandler>,
+    sender: SyncSender<MixerEvent>,
+    receiver: Receiver<MixerEvent>,
+    controllers: HashMap<String, Box<dyn Controller>>,
     profile: Profile,
-    mixer_handler: Rc<RefCell<SinkController>>,
+    backend: Rc<RefCell<dyn AudioBackend>>,
+    // Last feedback message sent to each LED, motor fader and LED ring of each device
+    feedback_state: HashMap<(String, MessageKind, bool, u8, u16), MidiMessage>,
+    // Mackie Control strips whose fader is held, their motors must not fight the hand
+    touched_strips: HashSet<(String, u8)>,
+    // Volume each fader stood for when it last moved, keyed like feedback_state
+    fader_state: HashMap<(String, MessageKind, bool, u8, u16), f64>,
+    // Text last written to each row of each scribble strip
+    display_state: HashMap<(String, u8, u8), String>,
+    osc: Option<OscServer>,
+    // Last state message sent to each OSC client for each address
+    osc_state: HashMap<(SocketAddr, String), OscMessage>,
 }
 
 impl MidiMixer {
-    pub fn new(profile: Profile) -> Result<MidiMixer, Box<dyn Error>> {
-        let midi_handler = Arc::new(Mutex::new(MidiHandler::new()));
-        let mut controller = MidiController::new(Arc::clone(&midi_handler));
-        controller.connect_input(&profile.get_midi_controller_name())?;
+    pub fn new(
+        profile: Profile,
+        backend: Rc<RefCell<dyn AudioBackend>>,
+    ) -> Result<MidiMixer, Box<dyn Error>> {
+        MidiMixer::with_controllers(profile, backend, |device, config, sender| {
+            Ok(Box::new(MidiController::open(device, config, sender)?))
+        })
+    }
+
+    // Like new, with every device of the profile opened by connect
+    pub fn with_controllers(
+        profile: Profile,
+        backend: Rc<RefCell<dyn AudioBackend>>,
+        connect: impl Fn(
+            &str,
+            &DeviceConfig,
+            SyncSender<MixerEvent>,
+        ) -> Result<Box<dyn Controller>, Box<dyn Error>>,
+    ) -> Result<MidiMixer, Box<dyn Error>> {
+        let (sender, receiver) = mpsc::sync_channel(EVENT_QUEUE_SIZE);
+        let mut controllers = HashMap::new();
+        for (device, config) in profile.get_devices() {
+            controllers.insert(device.clone(), connect(device, config, sender.clone())?);
+        }
+
+        let osc = profile
+            .get_osc()
+            .map(|config| OscServer::new(config, sender.clone()))
+            .transpose()?;
+
+        // Dropped when the queue is full, a sync is already pending then
+        let audio_sender = sender.clone();
+        backend.borrow_mut().subscribe(Box::new(move || {
+            let _ = audio_sender.try_send(MixerEvent::AudioChanged);
+        }))?;
+
         Ok(MidiMixer {
-            midi_handler: Arc::clone(&midi_handler),
-            controller,
+            sender,
+            receiver,
+            controllers,
             profile,
-            mixer_handler: Rc::new(RefCell::new(SinkController::create().unwrap())),
+            backend,
+            feedback_state: HashMap::new(),
+            fader_state: HashMap::new(),
+            touched_strips: HashSet::new(),
+            display_state: HashMap::new(),
+            osc,
+            osc_state: HashMap::new(),
         })
     }
 
-    fn get_applications(&mut self) -> Result<Vec<Application>, ControllerError> {
-        let applications = self.mixer_handler.borrow_mut().list_applications()?;
+    fn get_applications(&mut self) -> Result<Vec<Application>, BackendError> {
+        let applications = self.backend.borrow_mut().list_applications()?;
         Ok(applications
-            .iter()
-            .map(|app| {
-                Application::new(
-                    app.index,
-                    match app.proplist.get("application.process.binary") {
-                        Some(binary_name) => std::str::from_utf8(binary_name).unwrap().to_string(),
-                        None => "".to_string(),
-                    },
-                    Rc::clone(&self.mixer_handler),
-                )
-            })
+            .into_iter()
+            .map(|app| Application::new(app.index, app.name, Rc::clone(&self.backend)))
             .collect())
     }
 
-    fn get_playback_devices(&mut self) -> Result<Vec<OutputDevice>, ControllerError> {
-        let devices = self.mixer_handler.borrow_mut().list_devices()?;
+    fn get_playback_devices(&mut self) -> Result<Vec<OutputDevice>, BackendError> {
+        let devices = self.backend.borrow_mut().list_devices()?;
         Ok(devices
-            .iter()
-            .map(|device| {
-                OutputDevice::new(
-                    device.index,
-                    device.description.clone().unwrap_or("".to_string()),
-                    Rc::clone(&self.mixer_handler),
-                )
+            .into_iter()
+            .map(|device| OutputDevice::new(device.index, device.name, Rc::clone(&self.backend)))
+            .collect())
+    }
+
+    fn get_recording_streams(&mut self) -> Result<Vec<RecordingStream>, BackendError> {
+        let recordings = self.backend.borrow_mut().list_recording_streams()?;
+        Ok(recordings
+            .into_iter()
+            .map(|recording| {
+                RecordingStream::new(recording.index, recording.name, Rc::clone(&self.backend))
             })
             .collect())
     }
 
+    fn get_input_devices(&mut self) -> Result<Vec<InputDevice>, BackendError> {
+        let sources = self.backend.borrow_mut().list_sources()?;
+        Ok(sources
+            .into_iter()
+            .map(|source| InputDevice::new(source.index, source.name, Rc::clone(&self.backend)))
+            .collect())
+    }
+
+    fn get_gain_stages(&mut self) -> Result<Vec<GainStage>, BackendError> {
+        let stages = self.backend.borrow_mut().list_gain_stages()?;
+        Ok(stages
+            .into_iter()
+            .map(|stage| GainStage::new(stage.index, stage.name, Rc::clone(&self.backend)))
+            .collect())
+    }
+
+    // Applications and recording streams match by binary name, ignoring case
+    fn matches_binary(sink_name: &str, binary_name: &str) -> bool {
+        sink_name.trim().to_ascii_lowercase()
+            == binary_name
+                .trim()
+                .to_ascii_lowercase()
+                .trim_end_matches('\0')
+    }
+
+    // Every write goes through here, so the limits of the target's groups always apply
     fn get_volume_control(
         &mut self,
         sink_name: String,
-    ) -> Result<Option<Box<dyn VolumeControl>>, ControllerError> {
-        let applications = self.get_applications()?;
-        for app in applications {
-            println!("{}", app.get_name());
-            if sink_name.trim().to_ascii_lowercase()
-                == app
-                    .get_name()
-                    .trim()
-                    .to_ascii_lowercase()
-                    .trim_end_matches('\0')
-            {
-                return Ok(Some(Box::new(app)));
+    ) -> Result<Option<Box<dyn VolumeControl>>, BackendError> {
+        let limits = self.profile.get_limits(&sink_name);
+        Ok(self
+            .find_volume_control(sink_name)?
+            .map(|control| Box::new(Limited::new(control, limits)) as Box<dyn VolumeControl>))
+    }
+
+    // Playback targets are searched before recording ones with the same name, unless the
+    // target names the kind it wants, see StreamKind::split_target
+    fn find_volume_control(
+        &mut self,
+        sink_name: String,
+    ) -> Result<Option<Box<dyn VolumeControl>>, BackendError> {
+        let (kind, name) = StreamKind::split_target(&sink_name);
+        let wanted = |wanted: StreamKind| kind.is_none_or(|kind| kind == wanted);
+
+        if wanted(StreamKind::Application) {
+            let applications = self.get_applications()?;
+            for app in applications {
+                if Self::matches_binary(name, app.get_name()) {
+                    return Ok(Some(Box::new(app)));
+                }
+            }
+        }
+
+        if wanted(StreamKind::OutputDevice) {
+            let devices = self.get_playback_devices()?;
+            for device in devices {
+                if device.get_name() == name {
+                    return Ok(Some(Box::new(device)));
+                }
+            }
+        }
+
+        if wanted(StreamKind::RecordingStream) {
+            let recordings = self.get_recording_streams()?;
+            for recording in recordings {
+                if Self::matches_binary(name, recording.get_name()) {
+                    return Ok(Some(Box::new(recording)));
+                }
+            }
+        }
+
+        if wanted(StreamKind::InputDevice) {
+            let sources = self.get_input_devices()?;
+            for source in sources {
+                if source.get_name() == name {
+                    return Ok(Some(Box::new(source)));
+                }
             }
         }
 
-        let devices = self.get_playback_devices()?;
-        for device in devices {
-            if device.get_name() == sink_name {
-                return Ok(Some(Box::new(device)));
+        if wanted(StreamKind::GainStage) {
+            let stages = self.get_gain_stages()?;
+            for stage in stages {
+                if stage.get_name() == name {
+                    return Ok(Some(Box::new(stage)));
+                }
             }
         }
 
         Ok(None)
     }
 
-    pub fn update(&mut self) -> Result<(), ControllerError> {
-        let mut handler = self.midi_handler.lock().unwrap();
-
-        if handler.is_available() {
-            let message = handler.get_last_message();
-            drop(handler);
+    // Only the final position of a fader burst matters, every other event is kept in order
+    fn coalesce(&self, messages: Vec<(String, MidiMessage)>) -> Vec<(String, MidiMessage)> {
+        messages
+            .iter()
+            .enumerate()
+            .filter(|(i, (device, message))| {
+                self.profile.get_volume_control(device, message).is_none()
+                    || !messages[i + 1..].iter().any(|(later_device, later)| {
+                        later_device == device && later.same_control(message)
+                    })
+            })
+            .map(|(_, event)| event.clone())
+            .collect()
+    }
 
-            if let Some((sink_name, button)) =
-                self.profile.get_mute(message.channel, message.control)
+    fn handle_message(&mut self, device: &str, message: MidiMessage) -> Result<(), BackendError> {
+        if self.profile.get_protocol(device) == Protocol::Mackie {
+            if let Some((strip, StripControl::FaderTouch, touched)) = mackie::decode_note(&message)
             {
-                if button.triggered(message.value) {
-                    if let Some(volume_control) = self.get_volume_control(sink_name)? {
-                        volume_control.toggle_mute()?;
+                self.touch_strip(device, strip, touched);
+            }
+        }
+
+        if let Some((sink_name, button)) = self.profile.get_mute(device, &message) {
+            if button.triggered(message.value()) {
+                if let Some(volume_control) = self.get_volume_control(sink_name)? {
+                    volume_control.toggle_mute()?;
+                }
+            }
+        }
+
+        if let Some((sink_name, fader)) = self.profile.get_volume_control(device, &message) {
+            let limits = self.profile.get_limits(&sink_name);
+            let percent = limits.to_volume(fader.to_percentage(message.value()));
+            let key = (
+                device.to_string(),
+                message.kind(),
+                message.is_high_resolution(),
+                message.channel(),
+                message.control(),
+            );
+            let previous = self.fader_state.insert(key, percent);
+
+            if let Some(volume_control) = self.get_volume_control(sink_name)? {
+                let takeover = fader.takeover();
+                // Only taking over needs to know where the target is. Outside the limits
+                // the fader could never reach it, it is met at the nearest limit instead.
+                let volume = match takeover {
+                    Takeover::Jump => Some(percent),
+                    _ => {
+                        let current = limits.clamp(volume_control.get_volume()?);
+                        takeover.apply(previous, percent, current, limits)
                     }
+                };
+                if let Some(volume) = volume {
+                    volume_control.set_volume(volume)?;
                 }
             }
+        }
 
-            if let Some((sink_name, fader)) = self
-                .profile
-                .get_volume_control(message.channel, message.control)
-            {
-                let percent = fader.to_percentage(message.value);
+        if let Some((sink_name, encoder)) = self.profile.get_encoder(device, &message) {
+            let delta = encoder.to_delta(message.value());
 
-                if let Some(volume_control) = self.get_volume_control(sink_name)? {
-                    volume_control.set_volume(percent)?;
+            if let Some(volume_control) = self.get_volume_control(sink_name)? {
+                volume_control.change_volume(delta)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Only the last volume sent to an address counts, like with MIDI faders
+    fn coalesce_osc(messages: Vec<(SocketAddr, OscMessage)>) -> Vec<(SocketAddr, OscMessage)> {
+        messages
+            .iter()
+            .enumerate()
+            .filter(|(i, (_, message))| {
+                !message.address.ends_with("/volume")
+                    || !messages[i + 1..]
+                        .iter()
+                        .any(|(_, later)| later.address == message.address)
+            })
+            .map(|(_, event)| event.clone())
+            .collect()
+    }
+
+    // /mixer/<group>/volume takes 0.0 to 1.0, /mixer/<group>/mute sets the mute state
+    // from its argument or toggles it without one
+    fn handle_osc(&mut self, from: SocketAddr, message: OscMessage) -> Result<(), BackendError> {
+        if let Some(server) = self.osc.as_mut() {
+            // A new client gets the full state, a dropped one doesn't keep what it was sent
+            if server.register(from) {
+                self.osc_state
+                    .retain(|(client, _), _| server.clients().contains(client));
+            }
+        }
+
+        let Some((group, parameter)) = osc::split_address(&message.address) else {
+            return Ok(());
+        };
+        let Some(sink_name) = self.profile.get_group_target(group) else {
+            return Ok(());
+        };
+        let Some(volume_control) = self.get_volume_control(sink_name)? else {
+            return Ok(());
+        };
+        let value = message.args.first().and_then(|arg| arg.as_f64());
+
+        match (parameter, value) {
+            ("volume", Some(volume)) => volume_control.set_volume(volume)?,
+            ("mute", Some(muted)) if muted >= 0.5 => volume_control.mute()?,
+            ("mute", Some(_)) => volume_control.unmute()?,
+            ("mute", None) => volume_control.toggle_mute()?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn touch_strip(&mut self, device: &str, strip: u8, touched: bool) {
+        if touched {
+            self.touched_strips.insert((device.to_string(), strip));
+        } else {
+            self.touched_strips.remove(&(device.to_string(), strip));
+            // Forget what the motor was told so it snaps back to the actual level
+            self.feedback_state.retain(|key, message| {
+                key.0 != device
+                    || !matches!(message, MidiMessage::PitchBend { channel, .. } if *channel == strip)
+            });
+        }
+    }
+
+    fn send_feedback(&mut self, device: String, message: MidiMessage) {
+        if let MidiMessage::PitchBend { channel, .. } = message {
+            if self.touched_strips.contains(&(device.clone(), channel)) {
+                return;
+            }
+        }
+
+        let Some(controller) = self.controllers.get_mut(&device) else {
+            return;
+        };
+
+        let key = (
+            device,
+            message.kind(),
+            message.is_high_resolution(),
+            message.channel(),
+            message.control(),
+        );
+
+        if self.feedback_state.get(&key) == Some(&message) {
+            return;
+        }
+
+        match controller.send(&message) {
+            Ok(()) => {
+                self.feedback_state.insert(key, message);
+            }
+            Err(e) => eprintln!("Failed to send MIDI feedback: {}", e),
+        }
+    }
+
+    // Volume and mute state of a target, a missing target reads as silent
+    fn get_state(&mut self, sink_name: String) -> Result<(f64, bool), BackendError> {
+        Ok(match self.get_volume_control(sink_name)? {
+            Some(volume_control) => (volume_control.get_volume()?, volume_control.is_muted()?),
+            None => (0.0, false),
+        })
+    }
+
+    // Brings LEDs, motor faders and LED rings in line with the current volume and mute
+    // state of their targets, no matter who changed it
+    fn sync_feedback(&mut self) -> Result<(), BackendError> {
+        for sink_name in self.profile.get_feedback_targets() {
+            let Some((volume, muted)) = recover(self.get_state(sink_name.clone()))? else {
+                continue;
+            };
+
+            for (device, message) in self.profile.get_feedback(&sink_name, volume, muted) {
+                self.send_feedback(device, message);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_display(&mut self, device: String, strip: u8, row: u8, text: String) {
+        let key = (device, strip, row);
+        if self.display_state.get(&key) == Some(&text) {
+            return;
+        }
+
+        let Some(controller) = self.controllers.get_mut(&key.0) else {
+            return;
+        };
+
+        match controller.send_sysex(&mackie::lcd_sysex(strip, row, &text)) {
+            Ok(()) => {
+                self.display_state.insert(key, text);
+            }
+            Err(e) => eprintln!("Failed to write scribble strip: {}", e),
+        }
+    }
+
+    // Shows the group name on the upper row of its strip and the application or device
+    // it resolves to on the lower row, a dash while the target isn't there
+    fn sync_displays(&mut self) -> Result<(), BackendError> {
+        for (device, strip, group_name, sink_name) in self.profile.get_strips() {
+            let Some(volume_control) = recover(self.get_volume_control(sink_name))? else {
+                continue;
+            };
+            let target_name = match volume_control {
+                Some(volume_control) => volume_control.get_name().to_string(),
+                None => "\u{2014}".to_string(),
+            };
+
+            self.write_display(device.clone(), strip, 0, group_name);
+            self.write_display(device, strip, 1, target_name);
+        }
+
+        Ok(())
+    }
+
+    // Sends the volume and mute state of every group to every OSC client that doesn't
+    // have it yet
+    fn sync_osc(&mut self) -> Result<(), BackendError> {
+        if self.osc.is_none() {
+            return Ok(());
+        }
+
+        let mut messages = Vec::new();
+        for (group, sink_name) in self.profile.get_groups() {
+            let Some((volume, muted)) = recover(self.get_state(sink_name))? else {
+                continue;
+            };
+
+            messages.push(OscMessage::new(
+                osc::address(&group, "volume"),
+                vec![OscArg::Float(volume as f32)],
+            ));
+            messages.push(OscMessage::new(
+                osc::address(&group, "mute"),
+                vec![OscArg::Int(muted as i32)],
+            ));
+        }
+
+        let Some(server) = self.osc.as_ref() else {
+            return Ok(());
+        };
+        for &client in server.clients() {
+            for message in messages.iter() {
+                let key = (client, message.address.clone());
+                if self.osc_state.get(&key) == Some(message) {
+                    continue;
+                }
+
+                match server.send(client, message) {
+                    Ok(()) => {
+                        self.osc_state.insert(key, message.clone());
+                    }
+                    Err(e) => eprintln!("Failed to send OSC state to {}: {}", client, e),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), BackendError> {
+        self.sync_feedback()?;
+        self.sync_displays()?;
+        self.sync_osc()
+    }
+
+    // Returns true when a controller came or went, the feedback it missed needs a sync
+    fn supervise_controllers(&mut self) -> bool {
+        let mut changed = false;
+        for (device, controller) in self.controllers.iter_mut() {
+            if !controller.supervise() {
+                continue;
+            }
+            changed = true;
+
+            if controller.is_connected() {
+                println!("MIDI controller connected: {}", device);
+                // The surface lost its LEDs, motor positions and displays while it was gone
+                self.feedback_state.retain(|key, _| &key.0 != device);
+                self.fader_state.retain(|key, _| &key.0 != device);
+                self.display_state.retain(|key, _| &key.0 != device);
+                self.touched_strips.retain(|key| &key.0 != device);
+            } else {
+                println!("MIDI controller disconnected: {}", device);
+            }
+        }
+        changed
+    }
+
+    pub fn event_sender(&self) -> SyncSender<MixerEvent> {
+        self.sender.clone()
+    }
+
+    // Handles a batch of events, returns false once a shutdown was requested. An event
+    // that fails is logged and the rest of the batch still runs.
+    fn process(&mut self, events: Vec<MixerEvent>) -> Result<bool, BackendError> {
+        let mut messages = Vec::new();
+        let mut osc_messages = Vec::new();
+        let mut running = true;
+
+        for event in events {
+            match event {
+                MixerEvent::Midi(device, message) => messages.push((device, message)),
+                MixerEvent::Osc(from, message) => osc_messages.push((from, message)),
+                // Picked up by the sync after the batch
+                MixerEvent::AudioChanged => {}
+                MixerEvent::Shutdown => running = false,
+            }
+        }
+
+        for (device, message) in self.coalesce(messages) {
+            recover(self.handle_message(&device, message))?;
+        }
+
+        for (from, message) in MidiMixer::coalesce_osc(osc_messages) {
+            recover(self.handle_osc(from, message))?;
+        }
+
+        self.sync()?;
+
+        Ok(running)
+    }
+
+    // Sleeps until an event arrives, then handles everything that queued up meanwhile.
+    // Feedback is refreshed after every batch, changes made by other applications arrive
+    // as AudioChanged events from the backend. Controllers are supervised every
+    // SUPERVISE_INTERVAL even while events keep coming.
+    // Returns after a shutdown request or once the sound server is gone, the MIDI and audio
+    // backend connections are closed on drop.
+    pub fn run(mut self) -> Result<(), BackendError> {
+        self.sync()?;
+        let mut next_supervision = Instant::now() + SUPERVISE_INTERVAL;
+
+        loop {
+            if Instant::now() >= next_supervision {
+                if self.supervise_controllers() {
+                    self.sync()?;
                 }
+                next_supervision = Instant::now() + SUPERVISE_INTERVAL;
+            }
+
+            let timeout = next_supervision.saturating_duration_since(Instant::now());
+            let first = match self.receiver.recv_timeout(timeout) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            let mut events = vec![first];
+            events.extend(self.receiver.try_iter());
+
+            if !self.process(events)? {
+                break;
             }
         }
 
+        for controller in self.controllers.values_mut() {
+            controller.close();
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{
+        backend::mock::{Call, MockBackend},
+        midi_controller::{FakeController, FakeMidiSource, Sent, SentLog},
+        profile::{ProfileConfig, DEFAULT_DEVICE},
+    };
+
+    const CONFIG: &str = r#"
+midi_controller_name = "Test Controller"
+
+[devices.surface]
+input = "Test Surface"
+protocol = "mackie"
+
+[controls.buttons.button1]
+control = 48
+channel = 0
+trigger = 127
+led = { on = 127, off = 0 }
+
+[controls.buttons.button2]
+control = 49
+channel = 0
+trigger = 127
+
+[controls.faders.fader1]
+channel = 0
+control = 0
+min = 0
+max = 127
+
+[controls.faders.fader2]
+channel = 0
+control = 1
+min = 0
+max = 127
+
+[controls.faders.fader3]
+channel = 0
+control = 2
+min = 0
+max = 127
+takeover = "pickup"
+
+[controls.faders.fader4]
+channel = 0
+control = 3
+min = 0
+max = 127
+takeover = "pickup"
+
+[controls.encoders.knob1]
+channel = 0
+control = 16
+step = 0.1
+
+[groups.music]
+volume_control = ["fader1"]
+mute = ["button1"]
+volume_encoder = ["knob1"]
+
+[groups.speakers]
+volume_control = []
+mute = []
+max_volume = 1.5
+
+[groups.games]
+volume_control = ["fader2", "fader4"]
+mute = []
+max_volume = 0.7
+
+[groups.mic]
+volume_control = []
+mute = ["button2"]
+
+[groups.recorder]
+volume_control = []
+mute = []
+
+[groups.voice]
+volume_control = []
+mute = []
+
+[groups.voice_capture]
+volume_control = []
+mute = []
+
+[groups.browser]
+volume_control = ["fader3"]
+mute = []
+
+[groups.desk]
+volume_control = []
+mute = []
+strip = 1
+strip_device = "surface"
+
+[mapping]
+music = "Spotify"
+speakers = "Built-in Audio"
+mic = "Webcam Microphone"
+recorder = "OBS"
+voice = "Discord"
+voice_capture = "record:Discord"
+games = "Game"
+browser = "Firefox"
+desk = "Desk"
+"#;
+
+    struct Harness {
+        mixer: MidiMixer,
+        backend: Rc<RefCell<MockBackend>>,
+        midi: FakeMidiSource,
+        sent: SentLog,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            Harness::with_config(CONFIG)
+        }
+
+        fn with_config(config: &str) -> Harness {
+            let config: ProfileConfig = toml::from_str(config).unwrap();
+            let backend = Rc::new(RefCell::new(MockBackend::default()));
+            let sent = SentLog::default();
+            let mixer = MidiMixer::with_controllers(
+                Profile::new(&config).unwrap(),
+                backend.clone(),
+                |device, _, _| Ok(Box::new(FakeController::new(device, sent.clone()))),
+            )
+            .unwrap();
+            let midi = FakeMidiSource::new(DEFAULT_DEVICE, mixer.event_sender());
+
+            Harness {
+                mixer,
+                backend,
+                midi,
+                sent,
+            }
+        }
+
+        // Handles everything queued so far as one batch, like the run loop does
+        fn process(&mut self) -> bool {
+            let events: Vec<MixerEvent> = self.mixer.receiver.try_iter().collect();
+            self.mixer.process(events).unwrap()
+        }
+
+        // Everything sent to one device so far
+        fn sent(&self, device: &str) -> Vec<Sent> {
+            self.sent
+                .borrow()
+                .iter()
+                .filter(|(sent_to, _)| sent_to == device)
+                .map(|(_, sent)| sent.clone())
+                .collect()
+        }
+
+        // Every position sent to the motor of a strip's fader
+        fn motor(&self, device: &str, strip: u8) -> Vec<u16> {
+            self.sent(device)
+                .into_iter()
+                .filter_map(|sent| match sent {
+                    Sent::Message(MidiMessage::PitchBend { channel, value })
+                        if channel == strip =>
+                    {
+                        Some(value)
+                    }
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn fader_sets_application_volume() {
+        let mut harness = Harness::new();
+        let spotify = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "spotify", 0.5);
+
+        harness.midi.send(&[0xB0, 0, 127]);
+        harness.process();
+        assert_eq!(
+            harness
+                .backend
+                .borrow()
+                .volume(StreamKind::Application, spotify),
+            1.0
+        );
+
+        harness.midi.send(&[0xB0, 0, 0]);
+        harness.process();
+        assert_eq!(
+            harness
+                .backend
+                .borrow()
+                .volume(StreamKind::Application, spotify),
+            0.0
+        );
+    }
+
+    #[test]
+    fn fader_burst_is_coalesced() {
+        let mut harness = Harness::new();
+        let spotify = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "spotify", 0.5);
+
+        for value in [10, 20, 30, 127] {
+            harness.midi.send(&[0xB0, 0, value]);
+        }
+        harness.process();
+
+        assert_eq!(
+            harness.backend.borrow().calls(),
+            &[Call::SetVolume(StreamKind::Application, spotify, 1.0)]
+        );
+    }
+
+    #[test]
+    fn mute_button_toggles_on_trigger_value() {
+        let mut harness = Harness::new();
+        let spotify = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "spotify", 0.5);
+
+        harness.midi.send(&[0xB0, 48, 127]);
+        harness.midi.send(&[0xB0, 48, 0]);
+        harness.process();
+        assert!(harness
+            .backend
+            .borrow()
+            .muted(StreamKind::Application, spotify));
+
+        harness.midi.send(&[0xB0, 48, 127]);
+        harness.process();
+        assert!(!harness
+            .backend
+            .borrow()
+            .muted(StreamKind::Application, spotify));
+    }
+
+    #[test]
+    fn microphone_and_recording_stream_are_targets() {
+        let mut harness = Harness::new();
+        let mic =
+            harness
+                .backend
+                .borrow_mut()
+                .add(StreamKind::InputDevice, "Webcam Microphone", 0.5);
+        let obs = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::RecordingStream, "obs", 0.5);
+
+        harness.midi.send(&[0xB0, 49, 127]);
+        harness
+            .mixer
+            .event_sender()
+            .send(MixerEvent::Osc(
+                "127.0.0.1:9001".parse().unwrap(),
+                OscMessage::new(
+                    osc::address("recorder", "volume"),
+                    vec![OscArg::Float(0.25)],
+                ),
+            ))
+            .unwrap();
+        harness.process();
+
+        assert_eq!(
+            harness.backend.borrow().calls(),
+            &[
+                Call::SetMute(StreamKind::InputDevice, mic, true),
+                Call::SetVolume(StreamKind::RecordingStream, obs, 0.25),
+            ]
+        );
+    }
+
+    #[test]
+    fn kind_prefix_picks_recording_stream_of_the_same_name() {
+        let mut harness = Harness::new();
+        let playback = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "discord", 0.5);
+        let recording =
+            harness
+                .backend
+                .borrow_mut()
+                .add(StreamKind::RecordingStream, "discord", 0.5);
+
+        let sender = harness.mixer.event_sender();
+        for group in ["voice", "voice_capture"] {
+            sender
+                .send(MixerEvent::Osc(
+                    "127.0.0.1:9001".parse().unwrap(),
+                    OscMessage::new(osc::address(group, "volume"), vec![OscArg::Float(0.25)]),
+                ))
+                .unwrap();
+        }
+        harness.process();
+
+        assert_eq!(
+            harness.backend.borrow().calls(),
+            &[
+                Call::SetVolume(StreamKind::Application, playback, 0.25),
+                Call::SetVolume(StreamKind::RecordingStream, recording, 0.25),
+            ]
+        );
+    }
+
+    #[test]
+    fn groups_on_jack_drive_their_own_gain_stage() {
+        // Stream kinds other than gain stages don't exist on JACK
+        let config = CONFIG.replace("voice_capture = \"record:Discord\"\n", "");
+        let mut harness = Harness::with_config(&format!("backend = \"jack\"\n{}", config));
+        harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "spotify", 0.5);
+        let music = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::GainStage, "music", 1.0);
+        let games = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::GainStage, "games", 1.0);
+
+        harness.midi.send(&[0xB0, 0, 0]);
+        harness.midi.send(&[0xB0, 1, 127]);
+        harness.process();
+
+        assert_eq!(
+            harness.backend.borrow().calls(),
+            &[
+                Call::SetVolume(StreamKind::GainStage, music, 0.0),
+                Call::SetVolume(StreamKind::GainStage, games, 0.7),
+            ]
+        );
+    }
+
+    #[test]
+    fn group_limits_clamp_every_write() {
+        let mut harness = Harness::new();
+        let game = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "game", 0.5);
+        let speakers =
+            harness
+                .backend
+                .borrow_mut()
+                .add(StreamKind::OutputDevice, "Built-in Audio", 0.5);
+        let from: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+        let sender = harness.mixer.event_sender();
+
+        // The whole fader travel ends at the cap
+        harness.midi.send(&[0xB0, 1, 127]);
+        harness.process();
+        sender
+            .send(MixerEvent::Osc(
+                from,
+                OscMessage::new(osc::address("games", "volume"), vec![OscArg::Float(1.0)]),
+            ))
+            .unwrap();
+        sender
+            .send(MixerEvent::Osc(
+                from,
+                OscMessage::new(
+                    osc::address("speakers", "volume"),
+                    vec![OscArg::Float(1.25)],
+                ),
+            ))
+            .unwrap();
+        harness.process();
+
+        assert_eq!(
+            harness.backend.borrow().calls(),
+            &[
+                Call::SetVolume(StreamKind::Application, game, 0.7),
+                Call::SetVolume(StreamKind::Application, game, 0.7),
+                Call::SetVolume(StreamKind::OutputDevice, speakers, 1.25),
+            ]
+        );
+    }
+
+    #[test]
+    fn pickup_fader_waits_until_it_crosses_the_volume() {
+        let mut harness = Harness::new();
+        let firefox = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "firefox", 0.8);
+
+        harness.midi.send(&[0xB0, 2, 25]);
+        harness.process();
+        harness.midi.send(&[0xB0, 2, 64]);
+        harness.process();
+        assert!(harness.backend.borrow().calls().is_empty());
+
+        harness.midi.send(&[0xB0, 2, 127]);
+        harness.process();
+        harness.midi.send(&[0xB0, 2, 0]);
+        harness.process();
+        assert_eq!(
+            harness.backend.borrow().calls(),
+            &[
+                Call::SetVolume(StreamKind::Application, firefox, 1.0),
+                Call::SetVolume(StreamKind::Application, firefox, 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn pickup_fader_meets_a_volume_above_the_limit_at_the_limit() {
+        let mut harness = Harness::new();
+        let game = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "game", 1.0);
+
+        // 0.55 of the 0.7 the group allows
+        harness.midi.send(&[0xB0, 3, 100]);
+        harness.process();
+        assert!(harness.backend.borrow().calls().is_empty());
+
+        harness.midi.send(&[0xB0, 3, 127]);
+        harness.process();
+        assert_eq!(
+            harness.backend.borrow().calls(),
+            &[Call::SetVolume(StreamKind::Application, game, 0.7)]
+        );
+    }
+
+    #[test]
+    fn touched_strip_fader_holds_off_the_motor() {
+        let mut harness = Harness::new();
+        let desk = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "desk", 0.5);
+        let mut surface = FakeMidiSource::new("surface", harness.mixer.event_sender());
+
+        harness.process();
+        assert_eq!(harness.motor("surface", 0), [8192]);
+
+        // Strip 1 is bound to the group, its fader is Pitch Bend on channel 0
+        surface.send(&[0x90, 0x68, 0x7F]);
+        surface.send(&[0xE0, 0x7F, 0x7F]);
+        harness.process();
+        assert_eq!(
+            harness.backend.borrow().calls(),
+            &[Call::SetVolume(StreamKind::Application, desk, 1.0)]
+        );
+
+        // The hand on the fader wins over changes from elsewhere
+        harness
+            .backend
+            .borrow_mut()
+            .change(StreamKind::Application, desk, 0.8, false);
+        harness.process();
+        assert_eq!(harness.motor("surface", 0), [8192]);
+
+        // Released, the motor moves to where the volume actually is
+        surface.send(&[0x90, 0x68, 0x00]);
+        harness.process();
+        assert_eq!(harness.motor("surface", 0), [8192, 13106]);
+    }
+
+    #[test]
+    fn encoder_changes_volume_relatively() {
+        let mut harness = Harness::new();
+        let spotify = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "spotify", 0.5);
+
+        harness.midi.send(&[0xB0, 16, 1]);
+        harness.midi.send(&[0xB0, 16, 1]);
+        harness.process();
+        let volume = harness
+            .backend
+            .borrow()
+            .volume(StreamKind::Application, spotify);
+        assert!((volume - 0.7).abs() < 1e-9);
+
+        // Two's complement, 127 is one step down
+        harness.midi.send(&[0xB0, 16, 127]);
+        harness.process();
+        let volume = harness
+            .backend
+            .borrow()
+            .volume(StreamKind::Application, spotify);
+        assert!((volume - 0.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn missing_target_is_ignored() {
+        let mut harness = Harness::new();
+        let spotify = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "spotify", 0.5);
+        harness
+            .backend
+            .borrow_mut()
+            .remove(StreamKind::Application, spotify);
+
+        harness.midi.send(&[0xB0, 0, 127]);
+        harness.midi.send(&[0xB0, 48, 127]);
+        assert!(harness.process());
+        assert!(harness.backend.borrow().calls().is_empty());
+    }
+
+    #[test]
+    fn failed_write_does_not_stop_the_mixer() {
+        let mut harness = Harness::new();
+        let spotify = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "spotify", 0.5);
+
+        // The stream went away between listing and setting it
+        harness
+            .backend
+            .borrow_mut()
+            .fail_next_write(BackendError::Operation("No such entity".to_string()));
+        harness.midi.send(&[0xB0, 0, 127]);
+        harness.midi.send(&[0xB0, 48, 127]);
+        assert!(harness.process());
+        assert_eq!(
+            harness.backend.borrow().calls(),
+            &[Call::SetMute(StreamKind::Application, spotify, true)]
+        );
+
+        harness
+            .backend
+            .borrow_mut()
+            .fail_next_write(BackendError::Connection(
+                "Connection terminated".to_string(),
+            ));
+        harness.midi.send(&[0xB0, 0, 0]);
+        let events: Vec<MixerEvent> = harness.mixer.receiver.try_iter().collect();
+        assert!(matches!(
+            harness.mixer.process(events),
+            Err(BackendError::Connection(_))
+        ));
+    }
+
+    #[test]
+    fn osc_controls_groups_by_name() {
+        let mut harness = Harness::new();
+        let speakers =
+            harness
+                .backend
+                .borrow_mut()
+                .add(StreamKind::OutputDevice, "Built-in Audio", 0.5);
+        let from: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+        let sender = harness.mixer.event_sender();
+
+        sender
+            .send(MixerEvent::Osc(
+                from,
+                OscMessage::new(
+                    osc::address("speakers", "volume"),
+                    vec![OscArg::Float(0.25)],
+                ),
+            ))
+            .unwrap();
+        sender
+            .send(MixerEvent::Osc(
+                from,
+                OscMessage::new(osc::address("speakers", "mute"), vec![OscArg::Int(1)]),
+            ))
+            .unwrap();
+        harness.process();
+
+        assert_eq!(
+            harness.backend.borrow().calls(),
+            &[
+                Call::SetVolume(StreamKind::OutputDevice, speakers, 0.25),
+                Call::SetMute(StreamKind::OutputDevice, speakers, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn feedback_follows_changes_from_elsewhere() {
+        let mut harness = Harness::new();
+        let spotify = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "spotify", 0.5);
+        assert!(harness.backend.borrow().is_subscribed());
+
+        harness.process();
+
+        harness
+            .backend
+            .borrow_mut()
+            .change(StreamKind::Application, spotify, 0.5, true);
+        harness.process();
+
+        let led = |value| {
+            Sent::Message(MidiMessage::ControlChange {
+                channel: 0,
+                control: 48,
+                value,
+            })
+        };
+        assert_eq!(
+            harness
+                .sent(DEFAULT_DEVICE)
+                .into_iter()
+                .filter(|sent| *sent == led(0) || *sent == led(127))
+                .collect::<Vec<_>>(),
+            [led(0), led(127)]
+        );
+        assert!(harness.backend.borrow().calls().is_empty());
+    }
+
+    #[test]
+    fn strip_display_shows_group_and_target() {
+        let mut harness = Harness::new();
+        let displays = |harness: &Harness| -> Vec<Sent> {
+            harness
+                .sent("surface")
+                .into_iter()
+                .filter(|sent| matches!(sent, Sent::SysEx(_)))
+                .collect()
+        };
+
+        harness.process();
+        assert_eq!(
+            displays(&harness),
+            [
+                Sent::SysEx(mackie::lcd_sysex(0, 0, "desk")),
+                Sent::SysEx(mackie::lcd_sysex(0, 1, "\u{2014}")),
+            ]
+        );
+
+        let desk = harness
+            .backend
+            .borrow_mut()
+            .add(StreamKind::Application, "desk", 0.5);
+        harness.process();
+        harness
+            .backend
+            .borrow_mut()
+            .remove(StreamKind::Application, desk);
+        harness.process();
+        assert_eq!(
+            displays(&harness)[2..],
+            [
+                Sent::SysEx(mackie::lcd_sysex(0, 1, "desk")),
+                Sent::SysEx(mackie::lcd_sysex(0, 1, "\u{2014}")),
+            ]
+        );
+    }
+
+    #[test]
+    fn shutdown_stops_processing() {
+        let mut harness = Harness::new();
+        harness
+            .mixer
+            .event_sender()
+            .send(MixerEvent::Shutdown)
+            .unwrap();
+        assert!(!harness.process());
+    }
+}
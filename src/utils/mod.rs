@@ -1,4 +1,9 @@
+pub mod backend;
+pub mod curve;
+pub mod learn;
+pub mod mackie;
 pub mod midi_controller;
 pub mod midi_mixer;
+pub mod osc;
 pub mod profile;
 pub mod volume_control;
@@ -0,0 +1,391 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    net::{SocketAddr, UdpSocket},
+    sync::mpsc::SyncSender,
+    thread,
+};
+
+use super::midi_mixer::MixerEvent;
+
+// Largest datagram accepted, well above what control surfaces send
+const MAX_PACKET_SIZE: usize = 4096;
+const BUNDLE_TAG: &[u8] = b"#bundle\0";
+// Clients registered by sending to the server, the one heard from longest ago makes room
+const MAX_REGISTERED_CLIENTS: usize = 16;
+
+// Addresses are /mixer/<group>/volume and /mixer/<group>/mute
+pub const ADDRESS_PREFIX: &str = "/mixer";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct OscConfig {
+    // Local address the server listens on, e.g. "0.0.0.0:9000"
+    bind: SocketAddr,
+    // Clients receiving state updates from the start, anyone sending to the server is added
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    clients: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Double(f64),
+    String(String),
+    Bool(bool),
+}
+
+impl OscArg {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            OscArg::Int(value) => Some(*value as f64),
+            OscArg::Float(value) => Some(*value as f64),
+            OscArg::Double(value) => Some(*value),
+            OscArg::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+            OscArg::String(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+fn write_padded(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend_from_slice(bytes);
+    // OSC strings are null terminated and padded to a multiple of 4 bytes
+    let padding = 4 - bytes.len() % 4;
+    buffer.extend(std::iter::repeat_n(0, padding));
+}
+
+// Cursor over a packet, every read returns None once the packet is too short
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.position..self.position + len)?;
+        self.position += len;
+        Some(bytes)
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn read_u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn read_string(&mut self) -> Option<String> {
+        let rest = self.bytes.get(self.position..)?;
+        let len = rest.iter().position(|&b| b == 0)?;
+        let text = std::str::from_utf8(&rest[..len]).ok()?.to_string();
+        self.take((len / 4 + 1) * 4)?;
+        Some(text)
+    }
+}
+
+impl OscMessage {
+    pub fn new(address: String, args: Vec<OscArg>) -> OscMessage {
+        OscMessage { address, args }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        write_padded(&mut buffer, self.address.as_bytes());
+
+        let mut type_tags = String::from(",");
+        let mut arguments = Vec::new();
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(value) => {
+                    type_tags.push('i');
+                    arguments.extend_from_slice(&value.to_be_bytes());
+                }
+                OscArg::Float(value) => {
+                    type_tags.push('f');
+                    arguments.extend_from_slice(&value.to_be_bytes());
+                }
+                OscArg::Double(value) => {
+                    type_tags.push('d');
+                    arguments.extend_from_slice(&value.to_be_bytes());
+                }
+                OscArg::String(value) => {
+                    type_tags.push('s');
+                    write_padded(&mut arguments, value.as_bytes());
+                }
+                OscArg::Bool(value) => type_tags.push(if *value { 'T' } else { 'F' }),
+            }
+        }
+
+        write_padded(&mut buffer, type_tags.as_bytes());
+        buffer.extend(arguments);
+        buffer
+    }
+
+    // Returns every message of a packet, bundles are flattened and their time tags ignored.
+    // Malformed packets yield nothing.
+    pub fn parse(packet: &[u8]) -> Vec<OscMessage> {
+        let mut messages = Vec::new();
+        if OscMessage::parse_into(packet, &mut messages).is_none() {
+            messages.clear();
+        }
+        messages
+    }
+
+    fn parse_into(packet: &[u8], messages: &mut Vec<OscMessage>) -> Option<()> {
+        let mut reader = Reader {
+            bytes: packet,
+            position: 0,
+        };
+
+        if packet.starts_with(BUNDLE_TAG) {
+            reader.take(BUNDLE_TAG.len())?;
+            reader.read_u64()?; // Time tag
+            while reader.position < packet.len() {
+                let size = reader.read_u32()? as usize;
+                OscMessage::parse_into(reader.take(size)?, messages)?;
+            }
+            return Some(());
+        }
+
+        let address = reader.read_string()?;
+        if !address.starts_with('/') {
+            return None;
+        }
+
+        // Some old senders leave out the type tags, they can only mean no arguments
+        let type_tags = if reader.position < packet.len() {
+            reader.read_string()?
+        } else {
+            ",".to_string()
+        };
+
+        let mut args = Vec::new();
+        for tag in type_tags.strip_prefix(',')?.chars() {
+            let arg = match tag {
+                'i' => OscArg::Int(reader.read_u32()? as i32),
+                'f' => OscArg::Float(f32::from_bits(reader.read_u32()?)),
+                'd' => OscArg::Double(f64::from_bits(reader.read_u64()?)),
+                's' => OscArg::String(reader.read_string()?),
+                'T' => OscArg::Bool(true),
+                'F' => OscArg::Bool(false),
+                _ => return None,
+            };
+            args.push(arg);
+        }
+
+        messages.push(OscMessage { address, args });
+        Some(())
+    }
+}
+
+pub struct OscServer {
+    socket: UdpSocket,
+    // Clients from the config first, then registered ones from least to most recently heard
+    clients: Vec<SocketAddr>,
+    configured: usize,
+}
+
+impl OscServer {
+    // Binds the socket and forwards every received message to the mixer from a thread of its own
+    pub fn new(
+        config: &OscConfig,
+        sender: SyncSender<MixerEvent>,
+    ) -> Result<OscServer, Box<dyn Error>> {
+        let socket = UdpSocket::bind(config.bind)
+            .map_err(|e| format!("Failed to bind OSC server to {}: {}", config.bind, e))?;
+        let receiver = socket.try_clone()?;
+
+        thread::spawn(move || {
+            let mut buffer = [0u8; MAX_PACKET_SIZE];
+            loop {
+                let (len, from) = match receiver.recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!("Failed to receive OSC packet: {}", e);
+                        continue;
+                    }
+                };
+
+                for message in OscMessage::parse(&buffer[..len]) {
+                    // Stops once the mixer is gone
+                    if sender.send(MixerEvent::Osc(from, message)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        println!("OSC server listening on {}", config.bind);
+
+        Ok(OscServer {
+            socket,
+            clients: config.clients.clone(),
+            configured: config.clients.len(),
+        })
+    }
+
+    // Returns true when the client wasn't known yet. Past MAX_REGISTERED_CLIENTS the
+    // registered client that has been quiet the longest is dropped.
+    pub fn register(&mut self, client: SocketAddr) -> bool {
+        match self.clients.iter().position(|&known| known == client) {
+            Some(index) if index < self.configured => return false,
+            Some(index) => {
+                let client = self.clients.remove(index);
+                self.clients.push(client);
+                return false;
+            }
+            None => {}
+        }
+
+        if self.clients.len() - self.configured >= MAX_REGISTERED_CLIENTS {
+            let dropped = self.clients.remove(self.configured);
+            println!("OSC client dropped: {}", dropped);
+        }
+
+        println!("OSC client registered: {}", client);
+        self.clients.push(client);
+        true
+    }
+
+    pub fn clients(&self) -> &[SocketAddr] {
+        &self.clients
+    }
+
+    pub fn send(&self, client: SocketAddr, message: &OscMessage) -> Result<(), Box<dyn Error>> {
+        self.socket.send_to(&message.to_bytes(), client)?;
+        Ok(())
+    }
+}
+
+// Splits /mixer/<group>/<parameter> into group and parameter
+pub fn split_address(address: &str) -> Option<(&str, &str)> {
+    let rest = address.strip_prefix(ADDRESS_PREFIX)?.strip_prefix('/')?;
+    let (group, parameter) = rest.rsplit_once('/')?;
+    (!group.is_empty()).then_some((group, parameter))
+}
+
+pub fn address(group: &str, parameter: &str) -> String {
+    format!("{}/{}/{}", ADDRESS_PREFIX, group, parameter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, time::Duration};
+
+    fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut packet = BUNDLE_TAG.to_vec();
+        packet.extend_from_slice(&1u64.to_be_bytes());
+        for element in elements {
+            packet.extend_from_slice(&(element.len() as u32).to_be_bytes());
+            packet.extend_from_slice(element);
+        }
+        packet
+    }
+
+    fn server(clients: Vec<SocketAddr>) -> (OscServer, mpsc::Receiver<MixerEvent>) {
+        let (sender, receiver) = mpsc::sync_channel(16);
+        let config = OscConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            clients,
+        };
+        (OscServer::new(&config, sender).unwrap(), receiver)
+    }
+
+    #[test]
+    fn strings_are_padded_to_four_bytes() {
+        assert_eq!(
+            OscMessage::new("/abc".to_string(), vec![]).to_bytes(),
+            b"/abc\0\0\0\0,\0\0\0"
+        );
+        assert_eq!(
+            OscMessage::new("/ab".to_string(), vec![OscArg::String("hello".to_string())])
+                .to_bytes(),
+            b"/ab\0,s\0\0hello\0\0\0"
+        );
+    }
+
+    #[test]
+    fn arguments_are_tagged_by_type() {
+        let message = OscMessage::new(
+            "/mixer/music/volume".to_string(),
+            vec![
+                OscArg::Int(-2),
+                OscArg::Float(0.5),
+                OscArg::String("on".to_string()),
+            ],
+        );
+        let bytes = message.to_bytes();
+
+        assert_eq!(&bytes[20..28], b",ifs\0\0\0\0");
+        assert_eq!(&bytes[28..32], &(-2i32).to_be_bytes());
+        assert_eq!(&bytes[32..36], &0.5f32.to_be_bytes());
+        assert_eq!(&bytes[36..], b"on\0\0");
+        assert_eq!(OscMessage::parse(&bytes), vec![message]);
+    }
+
+    #[test]
+    fn nested_bundles_are_flattened() {
+        let volume = OscMessage::new("/mixer/a/volume".to_string(), vec![OscArg::Float(0.25)]);
+        let mute = OscMessage::new("/mixer/b/mute".to_string(), vec![OscArg::Int(1)]);
+        let packet = bundle(&[volume.to_bytes(), bundle(&[mute.to_bytes()])]);
+
+        assert_eq!(OscMessage::parse(&packet), vec![volume, mute]);
+        // A bundle cut short is dropped as a whole
+        assert_eq!(OscMessage::parse(&packet[..packet.len() - 2]), vec![]);
+    }
+
+    #[test]
+    fn messages_round_trip_over_udp() {
+        let (mut server, receiver) = server(vec![]);
+        let server_address = server.socket.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let request = OscMessage::new(address("music", "volume"), vec![OscArg::Float(0.5)]);
+
+        client.send_to(&request.to_bytes(), server_address).unwrap();
+        let Ok(MixerEvent::Osc(from, message)) = receiver.recv_timeout(Duration::from_secs(5))
+        else {
+            panic!("no OSC event");
+        };
+        assert_eq!(from, client.local_addr().unwrap());
+        assert_eq!(message, request);
+
+        assert!(server.register(from));
+        let reply = OscMessage::new(address("music", "mute"), vec![OscArg::Int(0)]);
+        server.send(from, &reply).unwrap();
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let (len, _) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(OscMessage::parse(&buffer[..len]), vec![reply]);
+    }
+
+    #[test]
+    fn quietest_registered_client_makes_room() {
+        let configured: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let client = |port: u16| SocketAddr::from(([127, 0, 0, 1], 10000 + port));
+        let (mut server, _receiver) = server(vec![configured]);
+
+        for port in 0..MAX_REGISTERED_CLIENTS as u16 {
+            assert!(server.register(client(port)));
+        }
+        // Heard from again, so client 1 is the quietest now
+        assert!(!server.register(client(0)));
+        assert!(server.register(client(100)));
+
+        assert_eq!(server.clients().len(), MAX_REGISTERED_CLIENTS + 1);
+        assert_eq!(server.clients()[0], configured);
+        assert!(server.clients().contains(&client(0)));
+        assert!(!server.clients().contains(&client(1)));
+        assert!(!server.register(configured));
+    }
+}
@@ -1,14 +1,32 @@
-use std::{collections::HashMap, fs, rc::Rc};
+use std::{collections::HashMap, rc::Rc};
 
 use serde::{Deserialize, Serialize};
 
-use super::volume_control::VolumeControl;
+use super::{
+    backend::{BackendKind, StreamKind, GAIN_STAGE_PREFIX},
+    curve::Curve,
+    mackie::{self, Protocol},
+    midi_controller::{MessageKind, MidiMessage, PortSelector},
+    osc::OscConfig,
+    volume_control::VolumeLimits,
+};
 
 #[derive(Debug)]
 pub enum ConfigError {
     FaderNotFound(String),
     ButtonNotFound(String),
+    EncoderNotFound(String),
     GroupNotFound(String),
+    DeviceNotFound(String),
+    InvalidDevice(String),
+    InvalidStrip(String),
+    InvalidPortPattern(regex::Error),
+    // Fader name + reason
+    InvalidCurve(String, String),
+    InvalidVolumeLimits(String),
+    InvalidResolution(String),
+    InvalidTarget(String),
+    InvalidFeedback(String),
 }
 
 impl std::fmt::Display for ConfigError {
@@ -18,41 +36,532 @@ impl std::fmt::Display for ConfigError {
             ConfigError::ButtonNotFound(button) => {
                 write!(f, "Fader not found in config: {}", button)
             }
+            ConfigError::EncoderNotFound(encoder) => {
+                write!(f, "Encoder not found in config: {}", encoder)
+            }
             ConfigError::GroupNotFound(group) => {
                 write!(f, "Group not found in config: {}", group)
             }
+            ConfigError::DeviceNotFound(device) => {
+                write!(f, "Device not found in config: {}", device)
+            }
+            ConfigError::InvalidDevice(device) => write!(
+                f,
+                "Device {} needs either an input port or a virtual port",
+                device
+            ),
+            ConfigError::InvalidPortPattern(e) => write!(f, "Invalid MIDI port regex: {}", e),
+            ConfigError::InvalidVolumeLimits(group) => write!(
+                f,
+                "Volume limits of group {} need 0.0 <= min_volume <= max_volume",
+                group
+            ),
+            ConfigError::InvalidResolution(fader) => write!(
+                f,
+                "Fader {} needs resolution = 7 or 14, 14-bit control changes only pair controls 0 to 31",
+                fader
+            ),
+            ConfigError::InvalidFeedback(control) => write!(
+                f,
+                "Control {} needs kind = \"control_change\" and a control below 96 for feedback = \"ring\"",
+                control
+            ),
+            ConfigError::InvalidTarget(group) => write!(
+                f,
+                "Group {} is on JACK, where app:, device:, source: and record: targets don't exist",
+                group
+            ),
+            ConfigError::InvalidCurve(fader, e) => {
+                write!(f, "Invalid volume curve of fader {}: {}", fader, e)
+            }
+            ConfigError::InvalidStrip(group) => write!(
+                f,
+                "Strip of group {} needs a device with protocol = \"mackie\" and a number from 1 to {}",
+                group,
+                mackie::STRIPS
+            ),
         }
     }
 }
 
 impl std::error::Error for ConfigError {}
 
+// Device of controls that don't name one, the one set up by midi_controller_name
+pub const DEFAULT_DEVICE: &str = "default";
+
+fn default_device() -> String {
+    DEFAULT_DEVICE.to_string()
+}
+
+fn is_default_device(device: &String) -> bool {
+    device == DEFAULT_DEVICE
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct DeviceConfig {
+    // Exact name, { contains = "..." }, { regex = "..." }, { index = n } or a list of them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    input: Option<PortSelector>,
+    // Port used for feedback, defaults to the input port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output: Option<PortSelector>,
+    // Name of a virtual input and output pair to create instead of connecting to hardware
+    #[serde(rename = "virtual", default, skip_serializing_if = "Option::is_none")]
+    virtual_port: Option<String>,
+    #[serde(default)]
+    protocol: Protocol,
+}
+
+impl DeviceConfig {
+    pub fn input(&self) -> Option<&PortSelector> {
+        self.input.as_ref()
+    }
+
+    pub fn output(&self) -> Option<&PortSelector> {
+        self.output.as_ref().or(self.input.as_ref())
+    }
+
+    pub fn virtual_port(&self) -> Option<&str> {
+        self.virtual_port.as_deref()
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Led {
+    #[serde(default = "default_led_on")]
+    on: u8,
+    #[serde(default)]
+    off: u8,
+    // Defaults to the channel of the button
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    channel: Option<u8>,
+}
+
+fn default_led_on() -> u8 {
+    127
+}
+
 #[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct Button {
-    control: u8,
+    #[serde(default = "default_device", skip_serializing_if = "is_default_device")]
+    device: String,
+    #[serde(default)]
+    kind: MessageKind,
+    #[serde(default)]
+    control: u16,
     channel: u8,
-    trigger: u8,
+    // Any value triggers when unset, e.g. velocity sensitive Note On pads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trigger: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    led: Option<Led>,
 }
 
 impl Button {
-    pub fn triggered(&self, val: u8) -> bool {
-        self.trigger == val
+    pub fn matches(&self, device: &str, message: &MidiMessage) -> bool {
+        // Buttons only ever bind to the raw 7-bit message of a CC pair
+        let paired_cc = matches!(message, MidiMessage::ControlChange14 { .. });
+
+        self.device == device
+            && self.kind == message.kind()
+            && !paired_cc
+            && self.channel == message.channel()
+            && self.control == message.control()
+    }
+
+    // Mute button of a Mackie Control strip, lit while muted
+    fn mackie_mute(device: String, strip: u8) -> Button {
+        Button {
+            device,
+            kind: MessageKind::NoteOn,
+            control: (mackie::MUTE_NOTE + strip) as u16,
+            channel: 0,
+            trigger: None,
+            led: Some(Led {
+                on: mackie::LED_ON,
+                off: mackie::LED_OFF,
+                channel: None,
+            }),
+        }
+    }
+
+    pub fn new(
+        device: String,
+        kind: MessageKind,
+        channel: u8,
+        control: u16,
+        trigger: Option<u16>,
+    ) -> Button {
+        Button {
+            device,
+            kind,
+            control,
+            channel,
+            trigger,
+            led: None,
+        }
+    }
+
+    pub fn triggered(&self, val: u16) -> bool {
+        self.trigger.is_none_or(|trigger| trigger == val)
+    }
+
+    // Message lighting the LED of the button while muted, None without LED config
+    pub fn led_message(&self, muted: bool) -> Option<MidiMessage> {
+        let led = self.led.as_ref()?;
+        let channel = led.channel.unwrap_or(self.channel);
+        let value = if muted { led.on } else { led.off };
+        let control = self.control as u8;
+
+        match self.kind {
+            MessageKind::NoteOn | MessageKind::NoteOff => Some(MidiMessage::NoteOn {
+                channel,
+                note: control,
+                velocity: value,
+            }),
+            MessageKind::ControlChange => Some(MidiMessage::ControlChange {
+                channel,
+                control,
+                value,
+            }),
+            _ => None,
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct Fader {
+    #[serde(default = "default_device", skip_serializing_if = "is_default_device")]
+    device: String,
+    #[serde(default)]
+    kind: MessageKind,
     channel: u8,
-    control: u8,
-    min: u8,
-    max: u8,
+    #[serde(default)]
+    control: u16,
+    // 14 pairs CC 0-31 with CC 32-63, Pitch Bend and (N)RPN are always 14-bit
+    #[serde(default = "default_resolution")]
+    resolution: u8,
+    min: u16,
+    max: u16,
+    #[serde(default, skip_serializing_if = "is_default_curve")]
+    curve: Curve,
+    #[serde(default)]
+    feedback: Feedback,
+    #[serde(default)]
+    takeover: Takeover,
+}
+
+fn is_default_curve(curve: &Curve) -> bool {
+    *curve == Curve::default()
+}
+
+fn default_resolution() -> u8 {
+    7
 }
 
 impl Fader {
-    pub fn to_percentage(&self, val: u8) -> f64 {
-        (val as f64 - self.min as f64) / (self.max as f64 - self.min as f64)
+    pub fn matches(&self, device: &str, message: &MidiMessage) -> bool {
+        let high_resolution = match self.kind {
+            MessageKind::ControlChange => self.resolution == 14,
+            _ => message.is_high_resolution(),
+        };
+
+        self.device == device
+            && self.kind == message.kind()
+            && high_resolution == message.is_high_resolution()
+            && self.channel == message.channel()
+            && self.control == message.control()
+    }
+
+    pub fn new(
+        device: String,
+        kind: MessageKind,
+        channel: u8,
+        control: u16,
+        resolution: u8,
+        min: u16,
+        max: u16,
+    ) -> Fader {
+        Fader {
+            device,
+            kind,
+            channel,
+            control,
+            resolution,
+            min,
+            max,
+            curve: Curve::default(),
+            feedback: Feedback::None,
+            takeover: Takeover::Jump,
+        }
+    }
+
+    // Motorized fader of a Mackie Control strip
+    fn mackie(device: String, strip: u8) -> Fader {
+        Fader {
+            device,
+            kind: MessageKind::PitchBend,
+            channel: strip,
+            control: 0,
+            resolution: 14,
+            min: 0,
+            max: mackie::FADER_MAX,
+            curve: Curve::default(),
+            feedback: Feedback::Value,
+            // The motor keeps the fader where the volume is
+            takeover: Takeover::Jump,
+        }
+    }
+
+    // Only controls 0-31 have an LSB partner, 32 above them
+    fn has_valid_resolution(&self) -> bool {
+        match (self.resolution, self.kind) {
+            (14, MessageKind::ControlChange) => self.control < 32,
+            (resolution, _) => resolution == 7 || resolution == 14,
+        }
+    }
+
+    pub fn takeover(&self) -> Takeover {
+        self.takeover
+    }
+
+    // Volume for a value of the fader, through its curve
+    pub fn to_percentage(&self, val: u16) -> f64 {
+        let position = (val as f64 - self.min as f64) / (self.max as f64 - self.min as f64);
+        self.curve.to_volume(position)
+    }
+
+    pub fn to_value(&self, level: f64) -> u16 {
+        let range = self.max as f64 - self.min as f64;
+        (self.min as f64 + self.curve.to_position(level) * range).round() as u16
+    }
+
+    // Message moving the fader to the given level, None without feedback config
+    pub fn feedback_message(&self, level: f64) -> Option<MidiMessage> {
+        let channel = self.channel;
+        let value = self.to_value(level);
+
+        match (self.feedback, self.kind) {
+            (Feedback::None, _) => None,
+            (Feedback::Ring, _) => Some(ring_message(
+                channel,
+                self.control,
+                self.curve.to_position(level),
+            )),
+            (Feedback::Value, MessageKind::ControlChange) if self.resolution == 14 => {
+                Some(MidiMessage::ControlChange14 {
+                    channel,
+                    control: self.control as u8,
+                    value,
+                })
+            }
+            (Feedback::Value, MessageKind::ControlChange) => Some(MidiMessage::ControlChange {
+                channel,
+                control: self.control as u8,
+                value: value as u8,
+            }),
+            (Feedback::Value, MessageKind::PitchBend) => {
+                Some(MidiMessage::PitchBend { channel, value })
+            }
+            (Feedback::Value, MessageKind::Nrpn) => Some(MidiMessage::Nrpn {
+                channel,
+                parameter: self.control,
+                value,
+            }),
+            (Feedback::Value, MessageKind::Rpn) => Some(MidiMessage::Rpn {
+                channel,
+                parameter: self.control,
+                value,
+            }),
+            (Feedback::Value, _) => None,
+        }
+    }
+}
+
+// Distance from the volume at which a picked up fader takes over
+const PICKUP_TOLERANCE: f64 = 0.02;
+
+// How a fader without motor takes over a volume that was changed elsewhere
+#[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Takeover {
+    // The volume jumps to the fader
+    #[default]
+    Jump,
+    // Nothing happens until the fader reaches or crosses the volume
+    Pickup,
+    // The volume moves towards the end the fader moves to, meeting it there
+    Scale,
+}
+
+impl Takeover {
+    // New volume for a fader moving from previous to volume while the target is at current,
+    // volumes are within the limits of the group. None leaves the target alone
+    pub fn apply(
+        self,
+        previous: Option<f64>,
+        volume: f64,
+        current: f64,
+        limits: VolumeLimits,
+    ) -> Option<f64> {
+        match (self, previous) {
+            (Takeover::Jump, _) => Some(volume),
+            (Takeover::Pickup, _) if (volume - current).abs() <= PICKUP_TOLERANCE => Some(volume),
+            (Takeover::Pickup, Some(previous)) => {
+                let crossed = (previous - current) * (volume - current) <= 0.0;
+                let picked_up = (previous - current).abs() <= PICKUP_TOLERANCE;
+                (crossed || picked_up).then_some(volume)
+            }
+            // Without a previous position there is no direction to scale in
+            (_, None) => None,
+            (Takeover::Scale, Some(previous)) if volume > previous && limits.max > previous => {
+                let ratio = (limits.max - current) / (limits.max - previous);
+                Some(current + (volume - previous) * ratio.max(0.0))
+            }
+            (Takeover::Scale, Some(previous)) if volume < previous && previous > limits.min => {
+                let ratio = (current - limits.min) / (previous - limits.min);
+                Some(current - (previous - volume) * ratio.max(0.0))
+            }
+            (Takeover::Scale, Some(_)) => None,
+        }
+    }
+}
+
+// What is sent back to a fader or encoder when the volume of its target changes
+#[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Feedback {
+    #[default]
+    None,
+    // The level in the control's own message, for motorized faders and absolute LED rings
+    Value,
+    // Mackie style LED ring, a CC 0x20 above the control with the position in the low nibble
+    Ring,
+}
+
+// The ring sits 0x20 above the control, which has to stay a data byte
+fn has_valid_feedback(feedback: Feedback, kind: MessageKind, control: u16) -> bool {
+    feedback != Feedback::Ring || (kind == MessageKind::ControlChange && control < 96)
+}
+
+fn ring_message(channel: u8, control: u16, level: f64) -> MidiMessage {
+    let position = (level.clamp(0.0, 1.0) * 11.0).round() as u8;
+
+    MidiMessage::ControlChange {
+        channel,
+        control: control as u8 + 0x20,
+        value: 0x20 | position, // Fill mode, lit from the left up to the position
+    }
+}
+
+// How an endless knob encodes the number of steps it was turned
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderMode {
+    #[default]
+    TwosComplement, // 1 = +1, 127 = -1
+    SignMagnitude, // 1 = +1, 65 = -1
+    BinaryOffset,  // 65 = +1, 63 = -1
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Encoder {
+    #[serde(default = "default_device", skip_serializing_if = "is_default_device")]
+    device: String,
+    #[serde(default)]
+    kind: MessageKind,
+    channel: u8,
+    #[serde(default)]
+    control: u16,
+    #[serde(default)]
+    encoding: EncoderMode,
+    // Volume change per step, 0.02 = 2%
+    #[serde(default = "default_step")]
+    step: f64,
+    #[serde(default)]
+    feedback: Feedback,
+}
+
+fn default_step() -> f64 {
+    0.02
+}
+
+impl Encoder {
+    pub fn matches(&self, device: &str, message: &MidiMessage) -> bool {
+        let paired_cc = matches!(message, MidiMessage::ControlChange14 { .. });
+
+        self.device == device
+            && self.kind == message.kind()
+            && !paired_cc
+            && self.channel == message.channel()
+            && self.control == message.control()
+    }
+
+    pub fn new(
+        device: String,
+        kind: MessageKind,
+        channel: u8,
+        control: u16,
+        encoding: EncoderMode,
+    ) -> Encoder {
+        Encoder {
+            device,
+            kind,
+            channel,
+            control,
+            encoding,
+            step: default_step(),
+            feedback: Feedback::None,
+        }
+    }
+
+    // V-Pot of a Mackie Control strip with its LED ring
+    fn mackie_vpot(device: String, strip: u8) -> Encoder {
+        Encoder {
+            device,
+            kind: MessageKind::ControlChange,
+            channel: 0,
+            control: (mackie::VPOT_CONTROL + strip) as u16,
+            encoding: EncoderMode::SignMagnitude,
+            step: default_step(),
+            feedback: Feedback::Ring,
+        }
+    }
+
+    pub fn to_delta(&self, val: u16) -> f64 {
+        let val = (val & 0x7F) as i16;
+        let steps = match self.encoding {
+            EncoderMode::TwosComplement if val >= 64 => val - 128,
+            EncoderMode::TwosComplement => val,
+            EncoderMode::SignMagnitude if val & 0x40 != 0 => -(val & 0x3F),
+            EncoderMode::SignMagnitude => val,
+            EncoderMode::BinaryOffset => val - 64,
+        };
+
+        steps as f64 * self.step
+    }
+
+    // LED ring message showing the given level, None without feedback config
+    pub fn feedback_message(&self, level: f64) -> Option<MidiMessage> {
+        match (self.feedback, self.kind) {
+            (Feedback::None, _) => None,
+            (Feedback::Ring, _) => Some(ring_message(self.channel, self.control, level)),
+            (Feedback::Value, MessageKind::ControlChange) => Some(MidiMessage::ControlChange {
+                channel: self.channel,
+                control: self.control as u8,
+                value: (level.clamp(0.0, 1.0) * 127.0).round() as u8,
+            }),
+            (Feedback::Value, _) => None,
+        }
     }
 }
 
@@ -61,37 +570,86 @@ impl Fader {
 struct ControlsConfig {
     buttons: HashMap<String, Button>,
     faders: HashMap<String, Fader>,
+    #[serde(default)]
+    encoders: HashMap<String, Encoder>,
 }
 
 struct Controls {
     buttons: HashMap<String, Rc<Button>>,
     faders: HashMap<String, Rc<Fader>>,
+    encoders: HashMap<String, Rc<Encoder>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
 struct GroupConfig {
     volume_control: Vec<String>, // References to fader keys
     mute: Vec<String>,           // References to button keys
+    #[serde(default)]
+    volume_encoder: Vec<String>, // References to encoder keys
+    // Mackie Control strip (1-based) whose fader, V-Pot and mute button control the group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    strip: Option<u8>,
+    // Device the strip is on, defaults to the default device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    strip_device: Option<String>,
+    // 1.0 is 100%, faders span the range from min_volume to max_volume
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_volume: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_volume: Option<f64>,
 }
 
-#[derive(PartialEq, Eq, Hash, Clone)]
+#[derive(Clone)]
 struct Group {
     name: String,
     volume_control: Vec<Rc<Fader>>,
     mute: Vec<Rc<Button>>,
+    volume_encoder: Vec<Rc<Encoder>>,
+    strip: Option<u8>,
+    strip_device: Option<String>,
+    limits: VolumeLimits,
+}
+
+// Group names are unique within a profile
+impl PartialEq for Group {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Group {}
+
+impl std::hash::Hash for Group {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct ProfileConfig {
-    midi_controller_name: String,
+    // Shorthand for the input, output and protocol of the default device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    protocol: Option<Protocol>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    midi_controller_name: Option<PortSelector>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    midi_output_name: Option<PortSelector>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backend: Option<BackendKind>,
+    #[serde(default)]
+    devices: HashMap<String, DeviceConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    osc: Option<OscConfig>,
     controls: ControlsConfig, // Include Controls to ensure buttons and faders are defined
     groups: HashMap<String, GroupConfig>,
     mapping: HashMap<String, String>, // Mapping of Group to application
 }
 pub struct Profile {
-    midi_controller_name: String,
+    backend: BackendKind,
+    devices: HashMap<String, DeviceConfig>,
+    osc: Option<OscConfig>,
     controls: Controls,
     mapping: HashMap<Group, String>,
 }
@@ -137,11 +695,78 @@ impl Profile {
         }
     }
 
-    pub fn get_midi_controller_name(&self) -> String {
-        self.midi_controller_name.clone()
+    fn get_encoders(
+        group: &GroupConfig,
+        encoders: &HashMap<String, Rc<Encoder>>,
+    ) -> Result<Vec<Rc<Encoder>>, ConfigError> {
+        group
+            .volume_encoder
+            .iter()
+            .map(|encoder| {
+                encoders
+                    .get(encoder)
+                    .map(Rc::clone)
+                    .ok_or_else(|| ConfigError::EncoderNotFound(encoder.clone()))
+            })
+            .collect()
+    }
+
+    pub fn has_control(&self, name: &str) -> bool {
+        self.controls.buttons.contains_key(name)
+            || self.controls.faders.contains_key(name)
+            || self.controls.encoders.contains_key(name)
+    }
+
+    pub fn get_devices(&self) -> &HashMap<String, DeviceConfig> {
+        &self.devices
+    }
+
+    pub fn get_protocol(&self, device: &str) -> Protocol {
+        self.devices
+            .get(device)
+            .map_or(Protocol::Generic, |d| d.protocol())
     }
 
     pub fn new(config: &ProfileConfig) -> Result<Profile, ConfigError> {
+        let mut devices = config.devices.clone();
+        if let Some(input) = &config.midi_controller_name {
+            devices.insert(
+                DEFAULT_DEVICE.to_string(),
+                DeviceConfig {
+                    input: Some(input.clone()),
+                    output: config.midi_output_name.clone(),
+                    virtual_port: None,
+                    protocol: config.protocol.unwrap_or_default(),
+                },
+            );
+        }
+
+        for (name, device) in devices.iter() {
+            if device.input.is_some() == device.virtual_port.is_some() {
+                return Err(ConfigError::InvalidDevice(name.clone()));
+            }
+        }
+
+        devices
+            .values()
+            .flat_map(|d| d.input().into_iter().chain(d.output()))
+            .flat_map(|port| port.matchers())
+            .try_for_each(|matcher| matcher.validate())
+            .map_err(ConfigError::InvalidPortPattern)?;
+
+        let control_devices = config
+            .controls
+            .buttons
+            .values()
+            .map(|b| &b.device)
+            .chain(config.controls.faders.values().map(|f| &f.device))
+            .chain(config.controls.encoders.values().map(|e| &e.device));
+        for device in control_devices {
+            if !devices.contains_key(device) {
+                return Err(ConfigError::DeviceNotFound(device.clone()));
+            }
+        }
+
         let buttons: HashMap<String, Rc<Button>> = config
             .controls
             .buttons
@@ -149,6 +774,19 @@ impl Profile {
             .map(|(key, button)| (key.clone(), Rc::new(button.clone())))
             .collect();
 
+        for (name, fader) in &config.controls.faders {
+            if !fader.has_valid_resolution() {
+                return Err(ConfigError::InvalidResolution(name.clone()));
+            }
+            if !has_valid_feedback(fader.feedback, fader.kind, fader.control) {
+                return Err(ConfigError::InvalidFeedback(name.clone()));
+            }
+            fader
+                .curve
+                .validate()
+                .map_err(|e| ConfigError::InvalidCurve(name.clone(), e))?;
+        }
+
         let faders: HashMap<String, Rc<Fader>> = config
             .controls
             .faders
@@ -156,31 +794,85 @@ impl Profile {
             .map(|(key, fader)| (key.clone(), Rc::new(fader.clone())))
             .collect();
 
+        for (name, encoder) in &config.controls.encoders {
+            if !has_valid_feedback(encoder.feedback, encoder.kind, encoder.control) {
+                return Err(ConfigError::InvalidFeedback(name.clone()));
+            }
+        }
+
+        let encoders: HashMap<String, Rc<Encoder>> = config
+            .controls
+            .encoders
+            .iter()
+            .map(|(key, encoder)| (key.clone(), Rc::new(encoder.clone())))
+            .collect();
+
         let groups: Vec<Group> = config
             .groups
             .iter()
             .map(|group| {
-                let faders =
-                    Profile::get_faders(group.1, &faders).map_err(|e| ConfigError::from(e))?;
+                let faders = Profile::get_faders(group.1, &faders)?;
+
+                let buttons = Profile::get_buttons(group.1, &buttons)?;
 
-                let buttons =
-                    Profile::get_buttons(group.1, &buttons).map_err(|e| ConfigError::from(e))?;
+                let mut faders = faders;
+                let mut buttons = buttons;
+                let mut volume_encoder = Profile::get_encoders(group.1, &encoders)?;
+
+                if let Some(strip) = group.1.strip {
+                    let device = group.1.strip_device.clone().unwrap_or_else(default_device);
+                    let mackie_device = devices
+                        .get(&device)
+                        .is_some_and(|d| d.protocol() == Protocol::Mackie);
+                    if !mackie_device || !(1..=mackie::STRIPS).contains(&strip) {
+                        return Err(ConfigError::InvalidStrip(group.0.to_owned()));
+                    }
+
+                    faders.push(Rc::new(Fader::mackie(device.clone(), strip - 1)));
+                    buttons.push(Rc::new(Button::mackie_mute(device.clone(), strip - 1)));
+                    volume_encoder.push(Rc::new(Encoder::mackie_vpot(device, strip - 1)));
+                }
+
+                let defaults = VolumeLimits::default();
+                let limits = VolumeLimits {
+                    min: group.1.min_volume.unwrap_or(defaults.min),
+                    max: group.1.max_volume.unwrap_or(defaults.max),
+                };
+                if !(0.0 <= limits.min && limits.min <= limits.max) {
+                    return Err(ConfigError::InvalidVolumeLimits(group.0.to_owned()));
+                }
 
                 Ok(Group {
                     name: group.0.to_owned(),
                     volume_control: faders,
                     mute: buttons,
+                    volume_encoder,
+                    strip: group.1.strip,
+                    strip_device: group.1.strip_device.clone(),
+                    limits,
                 })
             })
             .collect::<Result<Vec<Group>, ConfigError>>()?;
 
+        let backend = config.backend.unwrap_or_default();
         let mapping = config
             .mapping
             .iter()
             .map(|map| {
                 // Search for the group in the groups vector
                 if let Some(group) = groups.iter().find(|g| g.name == *map.0) {
-                    Ok((group.clone(), map.1.to_owned())) // Clone the group since we're borrowing
+                    // On JACK the mixer is the sound server, every mapped group drives a
+                    // gain stage of its own
+                    let target = match (backend, StreamKind::split_target(map.1).0) {
+                        (BackendKind::Jack, Some(kind)) if kind != StreamKind::GainStage => {
+                            return Err(ConfigError::InvalidTarget(group.name.clone()));
+                        }
+                        (BackendKind::Jack, _) if !map.1.is_empty() => {
+                            format!("{}{}", GAIN_STAGE_PREFIX, group.name)
+                        }
+                        _ => map.1.to_owned(),
+                    };
+                    Ok((group.clone(), target)) // Clone the group since we're borrowing
                 } else {
                     Err(ConfigError::GroupNotFound(map.0.to_owned()))
                 }
@@ -188,20 +880,30 @@ impl Profile {
             .collect::<Result<HashMap<Group, String>, ConfigError>>()?;
 
         Ok(Profile {
-            midi_controller_name: config.midi_controller_name.clone(),
-            controls: Controls { buttons, faders },
+            backend,
+            devices,
+            osc: config.osc.clone(),
+            controls: Controls {
+                buttons,
+                faders,
+                encoders,
+            },
             mapping,
         })
     }
 
     //Returns fader + application name/ output description, None if there is no application
-    pub fn get_volume_control(&self, channel: u8, control: u8) -> Option<(String, Rc<Fader>)> {
+    pub fn get_volume_control(
+        &self,
+        device: &str,
+        message: &MidiMessage,
+    ) -> Option<(String, Rc<Fader>)> {
         for map in &self.mapping {
             if let Some(fader) = map
                 .0
                 .volume_control
                 .iter()
-                .find(|&f| f.channel == channel && f.control == control)
+                .find(|&f| f.matches(device, message))
             {
                 if !map.1.is_empty() {
                     return Some((map.1.clone(), Rc::clone(fader)));
@@ -213,16 +915,33 @@ impl Profile {
     }
 
     //Returns button + application name/ output description, None if there is no application
-    pub fn get_mute(&self, channel: u8, control: u8) -> Option<(String, Rc<Button>)> {
+    pub fn get_mute(&self, device: &str, message: &MidiMessage) -> Option<(String, Rc<Button>)> {
+        for map in &self.mapping {
+            if let Some(button) = map.0.mute.iter().find(|&b| b.matches(device, message)) {
+                if !map.1.is_empty() {
+                    return Some((map.1.clone(), Rc::clone(button)));
+                }
+            }
+        }
+
+        None
+    }
+
+    //Returns encoder + application name/ output description, None if there is no application
+    pub fn get_encoder(
+        &self,
+        device: &str,
+        message: &MidiMessage,
+    ) -> Option<(String, Rc<Encoder>)> {
         for map in &self.mapping {
-            if let Some(button) = map
+            if let Some(encoder) = map
                 .0
-                .mute
+                .volume_encoder
                 .iter()
-                .find(|&f| f.channel == channel && f.control == control)
+                .find(|&e| e.matches(device, message))
             {
                 if !map.1.is_empty() {
-                    return Some((map.1.clone(), Rc::clone(button)));
+                    return Some((map.1.clone(), Rc::clone(encoder)));
                 }
             }
         }
@@ -230,6 +949,121 @@ impl Profile {
         None
     }
 
+    //Returns every application name/ output description with a control that needs feedback
+    pub fn get_feedback_targets(&self) -> Vec<String> {
+        let mut targets: Vec<String> = self
+            .mapping
+            .iter()
+            .filter(|(group, sink_name)| {
+                !sink_name.is_empty()
+                    && (group.mute.iter().any(|b| b.led.is_some())
+                        || group
+                            .volume_control
+                            .iter()
+                            .any(|f| f.feedback != Feedback::None)
+                        || group
+                            .volume_encoder
+                            .iter()
+                            .any(|e| e.feedback != Feedback::None))
+            })
+            .map(|(_, sink_name)| sink_name.clone())
+            .collect();
+
+        targets.sort();
+        targets.dedup();
+        targets
+    }
+
+    //Returns the device + LED, motor fader and LED ring messages showing the state of a target
+    pub fn get_feedback(
+        &self,
+        sink_name: &str,
+        volume: f64,
+        muted: bool,
+    ) -> Vec<(String, MidiMessage)> {
+        self.mapping
+            .iter()
+            .filter(|(_, name)| name.as_str() == sink_name)
+            .flat_map(|(group, _)| {
+                let leds = group
+                    .mute
+                    .iter()
+                    .filter_map(|b| Some((b.device.clone(), b.led_message(muted)?)));
+                // Controls show where the volume is within the range of their group
+                let position = group.limits.to_position(volume);
+                let faders = group
+                    .volume_control
+                    .iter()
+                    .filter_map(move |f| Some((f.device.clone(), f.feedback_message(position)?)));
+                let encoders = group
+                    .volume_encoder
+                    .iter()
+                    .filter_map(move |e| Some((e.device.clone(), e.feedback_message(position)?)));
+
+                leds.chain(faders)
+                    .chain(encoders)
+                    .collect::<Vec<(String, MidiMessage)>>()
+            })
+            .collect()
+    }
+
+    pub fn get_backend(&self) -> BackendKind {
+        self.backend
+    }
+
+    pub fn get_osc(&self) -> Option<&OscConfig> {
+        self.osc.as_ref()
+    }
+
+    //Returns the application name/ output description a group is mapped to
+    pub fn get_group_target(&self, group_name: &str) -> Option<String> {
+        self.mapping
+            .iter()
+            .find(|(group, _)| group.name == group_name)
+            .map(|(_, sink_name)| sink_name.clone())
+    }
+
+    //Returns the range every group mapped to the application/ output allows
+    pub fn get_limits(&self, sink_name: &str) -> VolumeLimits {
+        self.mapping
+            .iter()
+            .filter(|(_, name)| name.as_str() == sink_name)
+            .fold(None, |limits: Option<VolumeLimits>, (group, _)| {
+                Some(limits.map_or(group.limits, |l| l.intersect(group.limits)))
+            })
+            .unwrap_or_default()
+    }
+
+    //Returns every group name + application name/ output description, sorted by group
+    pub fn get_groups(&self) -> Vec<(String, String)> {
+        let mut groups: Vec<(String, String)> = self
+            .mapping
+            .iter()
+            .map(|(group, sink_name)| (group.name.clone(), sink_name.clone()))
+            .collect();
+        groups.sort();
+        groups
+    }
+
+    //Returns every device + Mackie Control strip (0-based) + group name + application name/ output description
+    pub fn get_strips(&self) -> Vec<(String, u8, String, String)> {
+        let mut strips: Vec<(String, u8, String, String)> = self
+            .mapping
+            .iter()
+            .filter_map(|(group, sink_name)| {
+                let device = group.strip_device.clone().unwrap_or_else(default_device);
+                group
+                    .strip
+                    .map(|strip| (device, strip - 1, group.name.clone(), sink_name.clone()))
+            })
+            .collect();
+
+        strips.sort();
+        strips
+    }
+
+    // Learn edits the config file itself, only the tests write a whole profile back out
+    #[cfg(test)]
     pub fn serialize(&self) -> ProfileConfig {
         let buttons: HashMap<String, Button> = self
             .controls
@@ -256,21 +1090,32 @@ impl Profile {
             })
             .collect();
 
+        let encoders: HashMap<String, Encoder> = self
+            .controls
+            .encoders
+            .iter()
+            .map(|(key, rc_encoder)| (key.clone(), (**rc_encoder).clone()))
+            .collect();
+
         let controls = ControlsConfig {
             buttons: buttons.clone(),
             faders: faders.clone(),
+            encoders: encoders.clone(),
         };
         let groups = self
             .mapping
             .clone()
-            .into_iter()
-            .map(|(group, sink_name)| {
+            .into_keys()
+            .map(|group| {
                 let volume_control = faders
                     .clone()
                     .into_iter()
                     .filter(|(_, fader)| {
                         for rc_fader in group.volume_control.clone() {
-                            if rc_fader.channel == fader.channel
+                            if rc_fader.device == fader.device
+                                && rc_fader.kind == fader.kind
+                                && rc_fader.resolution == fader.resolution
+                                && rc_fader.channel == fader.channel
                                 && rc_fader.control == fader.control
                             {
                                 return true;
@@ -286,7 +1131,9 @@ impl Profile {
                     .into_iter()
                     .filter(|(_, button)| {
                         for rc_button in group.mute.clone() {
-                            if rc_button.channel == button.channel
+                            if rc_button.device == button.device
+                                && rc_button.kind == button.kind
+                                && rc_button.channel == button.channel
                                 && rc_button.control == button.control
                             {
                                 return true;
@@ -297,9 +1144,27 @@ impl Profile {
                     .map(|(name, _)| name)
                     .collect();
 
+                let volume_encoder = encoders
+                    .iter()
+                    .filter(|(_, encoder)| {
+                        group
+                            .volume_encoder
+                            .iter()
+                            .any(|rc_encoder| **rc_encoder == **encoder)
+                    })
+                    .map(|(name, _)| name.clone())
+                    .collect();
+
                 let config = GroupConfig {
                     volume_control,
                     mute,
+                    volume_encoder,
+                    strip: group.strip,
+                    strip_device: group.strip_device,
+                    min_volume: Some(group.limits.min)
+                        .filter(|&min| min != VolumeLimits::default().min),
+                    max_volume: Some(group.limits.max)
+                        .filter(|&max| max != VolumeLimits::default().max),
                 };
                 (group.name, config)
             })
@@ -308,15 +1173,292 @@ impl Profile {
             .mapping
             .clone()
             .into_iter()
-            .map(|(group, sink_name)| (sink_name, group.name))
+            .map(|(group, sink_name)| (group.name, sink_name))
             .collect();
 
-        let config = ProfileConfig {
-            midi_controller_name: self.midi_controller_name.clone(),
+        ProfileConfig {
+            protocol: None,
+            midi_controller_name: None,
+            midi_output_name: None,
+            backend: Some(self.backend).filter(|&backend| backend != BackendKind::default()),
+            devices: self.devices.clone(),
+            osc: self.osc.clone(),
             controls,
             groups,
             mapping,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+midi_controller_name = "nanoKONTROL2"
+
+[devices.pads]
+input = { contains = "LPD8" }
+
+[controls.buttons.button1]
+control = 48
+channel = 0
+trigger = 127
+
+[controls.buttons.pad1]
+device = "pads"
+kind = "note_on"
+control = 36
+channel = 9
+
+[controls.faders.fader1]
+channel = 0
+control = 0
+min = 0
+max = 127
+
+[groups.group1]
+volume_control = ["fader1"]
+mute = ["button1"]
+
+[groups.mic]
+volume_control = []
+mute = ["pad1"]
+
+[mapping]
+group1 = "firefox"
+mic = "Built-in Audio"
+"#;
+
+    fn profile(config: &str) -> Result<Profile, ConfigError> {
+        Profile::new(&toml::from_str(config).unwrap())
+    }
+
+    fn cc(control: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange {
+            channel: 0,
+            control,
+            value,
+        }
+    }
+
+    #[test]
+    fn legacy_port_becomes_default_device() {
+        let profile = profile(CONFIG).unwrap();
+        assert!(profile.get_devices().contains_key(DEFAULT_DEVICE));
+        assert!(profile.get_devices().contains_key("pads"));
+    }
+
+    #[test]
+    fn controls_resolve_to_their_group_target() {
+        let profile = profile(CONFIG).unwrap();
+
+        let (sink_name, fader) = profile
+            .get_volume_control(DEFAULT_DEVICE, &cc(0, 64))
+            .unwrap();
+        assert_eq!(sink_name, "firefox");
+        assert_eq!(fader.to_percentage(127), 1.0);
+
+        let (sink_name, button) = profile.get_mute(DEFAULT_DEVICE, &cc(48, 127)).unwrap();
+        assert_eq!(sink_name, "firefox");
+        assert!(button.triggered(127));
+        assert!(!button.triggered(0));
+    }
+
+    #[test]
+    fn controls_only_match_their_device() {
+        let profile = profile(CONFIG).unwrap();
+        let pad = MidiMessage::NoteOn {
+            channel: 9,
+            note: 36,
+            velocity: 100,
+        };
+
+        assert!(profile.get_volume_control("pads", &cc(0, 64)).is_none());
+        assert!(profile.get_mute(DEFAULT_DEVICE, &pad).is_none());
+        assert_eq!(
+            profile
+                .get_mute("pads", &pad)
+                .map(|(sink_name, _)| sink_name),
+            Some("Built-in Audio".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_references_are_rejected() {
+        let missing_fader = CONFIG.replace(
+            r#"volume_control = ["fader1"]"#,
+            r#"volume_control = ["fader9"]"#,
+        );
+        assert!(matches!(
+            profile(&missing_fader),
+            Err(ConfigError::FaderNotFound(name)) if name == "fader9"
+        ));
+
+        let missing_device = CONFIG.replace(r#"device = "pads""#, r#"device = "keys""#);
+        assert!(matches!(
+            profile(&missing_device),
+            Err(ConfigError::DeviceNotFound(name)) if name == "keys"
+        ));
+
+        let empty_curve = CONFIG.replace(
+            "max = 127",
+            "max = 127\ncurve = { db = { min = 0, max = 0 } }",
+        );
+        assert!(matches!(
+            profile(&empty_curve),
+            Err(ConfigError::InvalidCurve(name, _)) if name == "fader1"
+        ));
+
+        let inverted_limits = CONFIG.replace(
+            "[groups.mic]",
+            "[groups.mic]\nmin_volume = 0.8\nmax_volume = 0.2",
+        );
+        assert!(matches!(
+            profile(&inverted_limits),
+            Err(ConfigError::InvalidVolumeLimits(name)) if name == "mic"
+        ));
+
+        let generic_strip = CONFIG.replace("[groups.mic]", "[groups.mic]\nstrip = 1");
+        assert!(matches!(
+            profile(&generic_strip),
+            Err(ConfigError::InvalidStrip(name)) if name == "mic"
+        ));
+    }
+
+    #[test]
+    fn fader_resolution_is_validated() {
+        let resolution = |resolution: &str, control: &str| {
+            profile(&CONFIG.replace(
+                "control = 0\nmin = 0",
+                &format!(
+                    "control = {}\nresolution = {}\nmin = 0",
+                    control, resolution
+                ),
+            ))
+        };
+
+        assert!(resolution("14", "0").is_ok());
+        assert!(resolution("14", "31").is_ok());
+        assert!(matches!(
+            resolution("16", "0"),
+            Err(ConfigError::InvalidResolution(name)) if name == "fader1"
+        ));
+        // CC 40 has no LSB partner at 72
+        assert!(matches!(
+            resolution("14", "40"),
+            Err(ConfigError::InvalidResolution(name)) if name == "fader1"
+        ));
+    }
+
+    #[test]
+    fn ring_feedback_needs_a_control_change_below_96() {
+        let ring = |kind: &str, control: &str| {
+            profile(&CONFIG.replace(
+                "control = 0\nmin = 0",
+                &format!(
+                    "kind = \"{}\"\ncontrol = {}\nfeedback = \"ring\"\nmin = 0",
+                    kind, control
+                ),
+            ))
         };
-        config
+
+        assert!(ring("control_change", "95").is_ok());
+        assert!(matches!(
+            ring("control_change", "96"),
+            Err(ConfigError::InvalidFeedback(name)) if name == "fader1"
+        ));
+        assert!(matches!(
+            ring("nrpn", "300"),
+            Err(ConfigError::InvalidFeedback(name)) if name == "fader1"
+        ));
+    }
+
+    #[test]
+    fn jack_rejects_stream_kind_targets() {
+        let jack = |target: &str| {
+            profile(&format!(
+                "backend = \"jack\"\n{}",
+                CONFIG.replace("group1 = \"firefox\"", &format!("group1 = \"{}\"", target))
+            ))
+        };
+
+        assert!(matches!(
+            jack("record:obs"),
+            Err(ConfigError::InvalidTarget(group)) if group == "group1"
+        ));
+        assert!(matches!(
+            jack("source:mic"),
+            Err(ConfigError::InvalidTarget(group)) if group == "group1"
+        ));
+        assert_eq!(
+            jack("firefox").unwrap().get_group_target("group1"),
+            Some("stage:group1".to_string())
+        );
+    }
+
+    #[test]
+    fn backend_defaults_to_pulseaudio() {
+        assert_eq!(
+            profile(CONFIG).unwrap().get_backend(),
+            BackendKind::Pulseaudio
+        );
+
+        let pipewire = profile(&format!("backend = \"pipewire\"\n{}", CONFIG)).unwrap();
+        assert_eq!(pipewire.get_backend(), BackendKind::Pipewire);
+        let reloaded = Profile::new(
+            &toml::from_str(&toml::to_string(&pipewire.serialize()).unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(reloaded.get_backend(), BackendKind::Pipewire);
+    }
+
+    #[test]
+    fn takeover_modes_meet_the_volume() {
+        let limits = VolumeLimits::default();
+
+        assert_eq!(Takeover::Jump.apply(None, 0.2, 0.8, limits), Some(0.2));
+
+        assert_eq!(Takeover::Pickup.apply(None, 0.2, 0.8, limits), None);
+        assert_eq!(Takeover::Pickup.apply(Some(0.2), 0.5, 0.8, limits), None);
+        assert_eq!(
+            Takeover::Pickup.apply(Some(0.5), 0.9, 0.8, limits),
+            Some(0.9)
+        );
+        assert_eq!(Takeover::Pickup.apply(None, 0.79, 0.8, limits), Some(0.79));
+
+        // Half way up from 0.2 covers half of the way from 0.8 to the top
+        assert_eq!(Takeover::Scale.apply(None, 0.6, 0.8, limits), None);
+        let up = Takeover::Scale.apply(Some(0.2), 0.6, 0.8, limits).unwrap();
+        assert!((up - 0.9).abs() < 1e-9);
+        assert_eq!(
+            Takeover::Scale.apply(Some(0.2), 0.0, 0.8, limits),
+            Some(0.0)
+        );
+        assert_eq!(
+            Takeover::Scale.apply(Some(0.6), 1.0, 0.8, limits),
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn serialized_profile_loads_again() {
+        let profile = profile(CONFIG).unwrap();
+        let reloaded =
+            Profile::new(&toml::from_str(&toml::to_string(&profile.serialize()).unwrap()).unwrap())
+                .unwrap();
+
+        assert_eq!(reloaded.get_groups(), profile.get_groups());
+        assert_eq!(reloaded.get_devices(), profile.get_devices());
+        assert!(reloaded
+            .get_mute(
+                "pads",
+                &MidiMessage::NoteOn {
+                    channel: 9,
+                    note: 36,
+                    velocity: 1
+                }
+            )
+            .is_some());
     }
 }
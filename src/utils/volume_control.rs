@@ -1,35 +1,48 @@
-use std::cell::Ref;
 use std::{cell::RefCell, rc::Rc};
 
-use pulsectl::controllers::AppControl;
-use pulsectl::controllers::DeviceControl;
-use pulsectl::controllers::SinkController;
-use pulsectl::ControllerError;
+use super::backend::{AudioBackend, BackendError, StreamKind};
 
 pub trait VolumeControl {
-    fn set_volume(&self, val: f64) -> Result<(), ControllerError>;
-    fn toggle_mute(&self) -> Result<(), ControllerError>;
-    fn mute(&self) -> Result<(), ControllerError>;
-    fn unmute(&self) -> Result<(), ControllerError>;
+    fn get_volume(&self) -> Result<f64, BackendError>;
+    fn set_volume(&self, val: f64) -> Result<(), BackendError>;
+
+    // Relative change from the current level, used by endless encoders
+    fn change_volume(&self, delta: f64) -> Result<(), BackendError> {
+        let volume = (self.get_volume()? + delta).clamp(0.0, 1.0);
+        self.set_volume(volume)
+    }
+
+    fn is_muted(&self) -> Result<bool, BackendError>;
+
+    fn toggle_mute(&self) -> Result<(), BackendError> {
+        if self.is_muted()? {
+            self.unmute()
+        } else {
+            self.mute()
+        }
+    }
+
+    fn mute(&self) -> Result<(), BackendError>;
+    fn unmute(&self) -> Result<(), BackendError>;
     fn get_name(&self) -> &str;
 }
 
 pub struct OutputDevice {
     index: u32,
     description: String,
-    handler: Rc<RefCell<SinkController>>,
+    backend: Rc<RefCell<dyn AudioBackend>>,
 }
 
 impl OutputDevice {
     pub fn new(
         index: u32,
         description: String,
-        handler: Rc<RefCell<SinkController>>,
+        backend: Rc<RefCell<dyn AudioBackend>>,
     ) -> OutputDevice {
         OutputDevice {
             index,
             description,
-            handler,
+            backend,
         }
     }
 }
@@ -39,72 +52,158 @@ impl VolumeControl for OutputDevice {
         &self.description
     }
 
-    fn set_volume(&self, val: f64) -> Result<(), ControllerError> {
-        let mut handler = self.handler.borrow_mut();
-        let current_volume: u8 = handler
-            .get_device_by_index(self.index)?
-            .volume
-            .avg()
-            .print()
-            .trim_end_matches('%')
-            .trim_ascii()
-            .parse()
-            .expect("Failed to convert volume to integer");
+    fn get_volume(&self) -> Result<f64, BackendError> {
+        self.backend
+            .borrow_mut()
+            .get_volume(StreamKind::OutputDevice, self.index)
+    }
+
+    fn set_volume(&self, val: f64) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_volume(StreamKind::OutputDevice, self.index, val)
+    }
 
-        let delta = val - (current_volume as f64 / 100.0);
+    fn is_muted(&self) -> Result<bool, BackendError> {
+        self.backend
+            .borrow_mut()
+            .is_muted(StreamKind::OutputDevice, self.index)
+    }
 
-        if delta < 0.0 {
-            handler.decrease_device_volume_by_percent(self.index, delta.abs());
-        } else {
-            handler.increase_device_volume_by_percent(self.index, delta);
+    fn mute(&self) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_mute(StreamKind::OutputDevice, self.index, true)
+    }
+
+    fn unmute(&self) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_mute(StreamKind::OutputDevice, self.index, false)
+    }
+}
+
+pub struct InputDevice {
+    index: u32,
+    description: String,
+    backend: Rc<RefCell<dyn AudioBackend>>,
+}
+
+impl InputDevice {
+    pub fn new(
+        index: u32,
+        description: String,
+        backend: Rc<RefCell<dyn AudioBackend>>,
+    ) -> InputDevice {
+        InputDevice {
+            index,
+            description,
+            backend,
         }
+    }
+}
 
-        Ok(())
+impl VolumeControl for InputDevice {
+    fn get_name(&self) -> &str {
+        &self.description
     }
 
-    fn toggle_mute(&self) -> Result<(), ControllerError> {
-        let muted = self
-            .handler
+    fn get_volume(&self) -> Result<f64, BackendError> {
+        self.backend
             .borrow_mut()
-            .get_device_by_index(self.index)?
-            .mute;
+            .get_volume(StreamKind::InputDevice, self.index)
+    }
 
-        if muted {
-            self.unmute()?;
-        } else {
-            self.mute()?;
+    fn set_volume(&self, val: f64) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_volume(StreamKind::InputDevice, self.index, val)
+    }
+
+    fn is_muted(&self) -> Result<bool, BackendError> {
+        self.backend
+            .borrow_mut()
+            .is_muted(StreamKind::InputDevice, self.index)
+    }
+
+    fn mute(&self) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_mute(StreamKind::InputDevice, self.index, true)
+    }
+
+    fn unmute(&self) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_mute(StreamKind::InputDevice, self.index, false)
+    }
+}
+
+// Input and output ports of the mixer on JACK, named after their group
+pub struct GainStage {
+    index: u32,
+    name: String,
+    backend: Rc<RefCell<dyn AudioBackend>>,
+}
+
+impl GainStage {
+    pub fn new(index: u32, name: String, backend: Rc<RefCell<dyn AudioBackend>>) -> GainStage {
+        GainStage {
+            index,
+            name,
+            backend,
         }
+    }
+}
 
-        Ok(())
+impl VolumeControl for GainStage {
+    fn get_name(&self) -> &str {
+        &self.name
     }
 
-    fn mute(&self) -> Result<(), ControllerError> {
-        self.handler
+    fn get_volume(&self) -> Result<f64, BackendError> {
+        self.backend
             .borrow_mut()
-            .set_device_mute_by_index(self.index, true);
-        Ok(())
+            .get_volume(StreamKind::GainStage, self.index)
     }
 
-    fn unmute(&self) -> Result<(), ControllerError> {
-        self.handler
+    fn set_volume(&self, val: f64) -> Result<(), BackendError> {
+        self.backend
             .borrow_mut()
-            .set_device_mute_by_index(self.index, false);
-        Ok(())
+            .set_volume(StreamKind::GainStage, self.index, val)
+    }
+
+    fn is_muted(&self) -> Result<bool, BackendError> {
+        self.backend
+            .borrow_mut()
+            .is_muted(StreamKind::GainStage, self.index)
+    }
+
+    fn mute(&self) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_mute(StreamKind::GainStage, self.index, true)
+    }
+
+    fn unmute(&self) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_mute(StreamKind::GainStage, self.index, false)
     }
 }
 
 pub struct Application {
     index: u32,
     name: String,
-    handler: Rc<RefCell<SinkController>>,
+    backend: Rc<RefCell<dyn AudioBackend>>,
 }
 
 impl Application {
-    pub fn new(index: u32, name: String, handler: Rc<RefCell<SinkController>>) -> Application {
+    pub fn new(index: u32, name: String, backend: Rc<RefCell<dyn AudioBackend>>) -> Application {
         Application {
             index,
             name,
-            handler,
+            backend,
         }
     }
 }
@@ -114,48 +213,171 @@ impl VolumeControl for Application {
         &self.name
     }
 
-    fn set_volume(&self, val: f64) -> Result<(), ControllerError> {
-        let mut handler = self.handler.borrow_mut();
-        let current_volume: u8 = handler
-            .get_app_by_index(self.index)?
-            .volume
-            .avg()
-            .print()
-            .trim_end_matches('%')
-            .trim_ascii()
-            .parse()
-            .expect("Failed to convert volume to integer");
+    fn get_volume(&self) -> Result<f64, BackendError> {
+        self.backend
+            .borrow_mut()
+            .get_volume(StreamKind::Application, self.index)
+    }
 
-        let delta = val - (current_volume as f64 / 100.0);
+    fn set_volume(&self, val: f64) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_volume(StreamKind::Application, self.index, val)
+    }
 
-        if delta < 0.0 {
-            handler.decrease_app_volume_by_percent(self.index, delta.abs());
-        } else {
-            handler.increase_app_volume_by_percent(self.index, delta);
+    fn is_muted(&self) -> Result<bool, BackendError> {
+        self.backend
+            .borrow_mut()
+            .is_muted(StreamKind::Application, self.index)
+    }
+
+    fn mute(&self) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_mute(StreamKind::Application, self.index, true)
+    }
+
+    fn unmute(&self) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_mute(StreamKind::Application, self.index, false)
+    }
+}
+
+pub struct RecordingStream {
+    index: u32,
+    name: String,
+    backend: Rc<RefCell<dyn AudioBackend>>,
+}
+
+impl RecordingStream {
+    pub fn new(
+        index: u32,
+        name: String,
+        backend: Rc<RefCell<dyn AudioBackend>>,
+    ) -> RecordingStream {
+        RecordingStream {
+            index,
+            name,
+            backend,
         }
+    }
+}
 
-        Ok(())
+impl VolumeControl for RecordingStream {
+    fn get_name(&self) -> &str {
+        &self.name
     }
 
-    fn toggle_mute(&self) -> Result<(), ControllerError> {
-        let muted = self.handler.borrow_mut().get_app_by_index(self.index)?.mute;
+    fn get_volume(&self) -> Result<f64, BackendError> {
+        self.backend
+            .borrow_mut()
+            .get_volume(StreamKind::RecordingStream, self.index)
+    }
 
-        if muted {
-            self.unmute();
-        } else {
-            self.mute();
+    fn set_volume(&self, val: f64) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_volume(StreamKind::RecordingStream, self.index, val)
+    }
+
+    fn is_muted(&self) -> Result<bool, BackendError> {
+        self.backend
+            .borrow_mut()
+            .is_muted(StreamKind::RecordingStream, self.index)
+    }
+
+    fn mute(&self) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_mute(StreamKind::RecordingStream, self.index, true)
+    }
+
+    fn unmute(&self) -> Result<(), BackendError> {
+        self.backend
+            .borrow_mut()
+            .set_mute(StreamKind::RecordingStream, self.index, false)
+    }
+}
+
+// Range a group's volume is kept in, 1.0 being 100%, above that is over-amplification
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VolumeLimits {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Default for VolumeLimits {
+    fn default() -> Self {
+        VolumeLimits { min: 0.0, max: 1.0 }
+    }
+}
+
+impl VolumeLimits {
+    pub fn clamp(self, volume: f64) -> f64 {
+        volume.clamp(self.min, self.max)
+    }
+
+    // Spreads the travel of a fader over the range
+    pub fn to_volume(self, position: f64) -> f64 {
+        self.min + position.clamp(0.0, 1.0) * (self.max - self.min)
+    }
+
+    pub fn to_position(self, volume: f64) -> f64 {
+        if self.max <= self.min {
+            return 0.0;
         }
+        ((volume - self.min) / (self.max - self.min)).clamp(0.0, 1.0)
+    }
+
+    // The range both limits allow, for targets shared by several groups
+    pub fn intersect(self, other: VolumeLimits) -> VolumeLimits {
+        let min = self.min.max(other.min);
+        VolumeLimits {
+            min,
+            max: self.max.min(other.max).max(min),
+        }
+    }
+}
+
+// Keeps every write to a target within the limits of its groups
+pub struct Limited {
+    control: Box<dyn VolumeControl>,
+    limits: VolumeLimits,
+}
+
+impl Limited {
+    pub fn new(control: Box<dyn VolumeControl>, limits: VolumeLimits) -> Limited {
+        Limited { control, limits }
+    }
+}
+
+impl VolumeControl for Limited {
+    fn get_name(&self) -> &str {
+        self.control.get_name()
+    }
+
+    fn get_volume(&self) -> Result<f64, BackendError> {
+        self.control.get_volume()
+    }
+
+    fn set_volume(&self, val: f64) -> Result<(), BackendError> {
+        self.control.set_volume(self.limits.clamp(val))
+    }
+
+    fn change_volume(&self, delta: f64) -> Result<(), BackendError> {
+        self.set_volume(self.get_volume()? + delta)
+    }
 
-        Ok(())
+    fn is_muted(&self) -> Result<bool, BackendError> {
+        self.control.is_muted()
     }
 
-    fn mute(&self) -> Result<(), ControllerError> {
-        self.handler.borrow_mut().set_app_mute(self.index, true)?;
-        Ok(())
+    fn mute(&self) -> Result<(), BackendError> {
+        self.control.mute()
     }
 
-    fn unmute(&self) -> Result<(), ControllerError> {
-        self.handler.borrow_mut().set_app_mute(self.index, false)?;
-        Ok(())
+    fn unmute(&self) -> Result<(), BackendError> {
+        self.control.unmute()
     }
 }